@@ -0,0 +1,195 @@
+import type { Crdt, CrdtOp, OpId } from "./messages";
+
+// a local replica of a crdt store, mirrors the merge rules of the server
+
+function compare(a: OpId, b: OpId): number {
+    if (a.counter !== b.counter) {
+        return a.counter - b.counter;
+    }
+    return a.site < b.site ? -1 : a.site > b.site ? 1 : 0;
+}
+
+function same(a: OpId | null, b: OpId | null): boolean {
+    return a !== null && b !== null && compare(a, b) === 0;
+}
+
+export class Replica {
+    public state: Crdt;
+    private site: string;
+    private clock = 0;
+
+    constructor(state: Crdt, site: string) {
+        this.state = state;
+        this.site = site;
+        this.observe();
+    }
+
+    // keeps the lamport clock ahead of every id in the state
+    private observe() {
+        const ids: OpId[] = this.state.kind === 'Text'
+            ? this.state.elements.map((e) => e.id)
+            : Object.values(this.state.entries).flat().map((e) => e.tag).concat(this.state.removed);
+        for (const id of ids) {
+            this.clock = Math.max(this.clock, id.counter);
+        }
+    }
+
+    private next_id(): OpId {
+        this.clock += 1;
+        return { counter: this.clock, site: this.site };
+    }
+
+    private integrate(op: CrdtOp): boolean | null {
+        if (op.op === 'Insert' || op.op === 'Delete') {
+            this.clock = Math.max(this.clock, op.id.counter);
+        } else if (op.op === 'Put') {
+            this.clock = Math.max(this.clock, op.tag.counter);
+        }
+
+        if (this.state.kind === 'Text') {
+            const elements = this.state.elements;
+            switch (op.op) {
+                case 'Insert': {
+                    if (elements.some((e) => same(e.id, op.id))) {
+                        return false;
+                    }
+
+                    let index = 0;
+                    if (op.after !== null) {
+                        const position = elements.findIndex((e) => same(e.id, op.after));
+                        if (position === -1) {
+                            return null;
+                        }
+                        index = position + 1;
+                    }
+
+                    while (index < elements.length && compare(elements[index].id, op.id) > 0) {
+                        index += 1;
+                    }
+                    elements.splice(index, 0, { id: op.id, value: op.value, deleted: false });
+                    return true;
+                }
+                case 'Delete': {
+                    const element = elements.find((e) => same(e.id, op.id));
+                    if (!element) {
+                        return null;
+                    }
+                    const changed = !element.deleted;
+                    element.deleted = true;
+                    return changed;
+                }
+                default:
+                    return false;
+            }
+        }
+
+        const map = this.state;
+        const remove_tags = (key: string, tags: OpId[]) => {
+            map.removed.push(...tags.filter((tag) => !map.removed.some((r) => same(r, tag))));
+            const entries = (map.entries[key] ?? []).filter((e) => !tags.some((tag) => same(tag, e.tag)));
+            if (entries.length === 0) {
+                delete map.entries[key];
+            } else {
+                map.entries[key] = entries;
+            }
+        };
+
+        switch (op.op) {
+            case 'Put': {
+                remove_tags(op.key, op.removes);
+                const entries = map.entries[op.key] ?? [];
+                if (!map.removed.some((r) => same(r, op.tag)) && !entries.some((e) => same(e.tag, op.tag))) {
+                    map.entries[op.key] = [...entries, { tag: op.tag, value: op.value }];
+                }
+                return true;
+            }
+            case 'Remove':
+                remove_tags(op.key, op.tags);
+                return true;
+            default:
+                return false;
+        }
+    }
+
+    public apply(ops: CrdtOp[]) {
+        const pending = this.state.kind === 'Text' ? this.state.pending : [];
+        for (const op of ops) {
+            if (this.integrate(op) === null) {
+                pending.push(op);
+            }
+        }
+
+        let progress = true;
+        while (progress && pending.length > 0) {
+            progress = false;
+            for (const op of pending.splice(0, pending.length)) {
+                const result = this.integrate(op);
+                if (result === null) {
+                    pending.push(op);
+                } else {
+                    progress = true;
+                }
+            }
+        }
+    }
+
+    public value(): any {
+        if (this.state.kind === 'Text') {
+            return this.state.elements.filter((e) => !e.deleted).map((e) => e.value).join('');
+        }
+
+        const object: Record<string, any> = {};
+        for (const [key, entries] of Object.entries(this.state.entries)) {
+            if (entries.length > 0) {
+                object[key] = entries.reduce((a, b) => (compare(a.tag, b.tag) >= 0 ? a : b)).value;
+            }
+        }
+        return object;
+    }
+
+    // operations for a local edit, apply them locally and send them to the server
+
+    public insert(index: number, text: string): CrdtOp[] {
+        if (this.state.kind !== 'Text') {
+            return [];
+        }
+
+        const visible = this.state.elements.filter((e) => !e.deleted);
+        let after: OpId | null = index > 0 ? visible[index - 1]?.id ?? null : null;
+
+        const ops: CrdtOp[] = [];
+        for (const value of text) {
+            const id = this.next_id();
+            ops.push({ op: 'Insert', id, after, value });
+            after = id;
+        }
+        return ops;
+    }
+
+    public delete(index: number, count: number): CrdtOp[] {
+        if (this.state.kind !== 'Text') {
+            return [];
+        }
+
+        const visible = this.state.elements.filter((e) => !e.deleted);
+        return visible.slice(index, index + count).map((e) => ({ op: 'Delete', id: e.id }));
+    }
+
+    public put(key: string, value: any): CrdtOp[] {
+        if (this.state.kind !== 'Map') {
+            return [];
+        }
+
+        const removes = (this.state.entries[key] ?? []).map((e) => e.tag);
+        return [{ op: 'Put', key, value, tag: this.next_id(), removes }];
+    }
+
+    public remove(key: string): CrdtOp[] {
+        if (this.state.kind !== 'Map') {
+            return [];
+        }
+
+        const tags = (this.state.entries[key] ?? []).map((e) => e.tag);
+        return [{ op: 'Remove', key, tags }];
+    }
+}
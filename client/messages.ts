@@ -1,9 +1,25 @@
 /** this file is automatically generated, do not edit **/
 
-export type ServerMessage = { type: "Update"; store: string; value: string };
-export type ClientMessage = { type: "Set"; store: string; value: string } | { type: "Get"; store: string } | { type: "Subscribe"; store: string; initial: string } | { type: "Unsubscribe"; store: string };
+export type ServerMessage = { type: "Update"; store: string; value: Value; version: number } | { type: "Patch"; store: string; ops: PatchOp[]; version: number } | { type: "Merge"; store: string; patch: any; version: number } | { type: "State"; store: string; state: Crdt; version: number } | { type: "Ops"; store: string; ops: CrdtOp[]; version: number } | { type: "History"; store: string; revisions: Revision[] } | { type: "Conflict"; store: string; expected_version: number; version: number; request_id: number | null } | { type: "Ack"; id: number; version: number } | { type: "Error"; code: ErrorCode; message: string; store: string | null; request_id: number | null };
+export type ClientMessage = { type: "Auth"; token: string; id: number | null } | { type: "Set"; store: string; value: string; id: number | null } | { type: "CompareAndSet"; store: string; expected_version: number; value: string; id: number | null } | { type: "SetBlob"; store: string; content_type: string | null; data: string; expected_version: number | null; id: number | null } | { type: "Get"; store: string; id: number | null } | { type: "History"; store: string; since_version: number | null; id: number | null } | { type: "Patch"; store: string; ops: PatchOp[]; expected_version: number | null; id: number | null } | { type: "Merge"; store: string; patch: any; expected_version: number | null; id: number | null } | { type: "Apply"; store: string; ops: CrdtOp[]; id: number | null } | { type: "Subscribe"; store: string; initial: string; kind: StoreKind | null; last_seen_version: number | null; coalesce: boolean | null; id: number | null } | { type: "Unsubscribe"; store: string; id: number | null };
+export type ErrorCode = "PermissionDenied" | "UnknownStore" | "InvalidMessage" | "TooLarge" | "InvalidValue" | "TokenExpired" | "RateLimited" | "TooManyStores" | "Internal";
+export type StoreKind = "Text" | "Json" | "CrdtText" | "CrdtMap" | "Blob";
+export type Value = string | { content_type: string; data: string };
+export type PatchOp = { op: "add"; path: string; value: any } | { op: "remove"; path: string } | { op: "replace"; path: string; value: any } | { op: "move"; from: string; path: string } | { op: "copy"; from: string; path: string } | { op: "test"; path: string; value: any };
+export type Revision = { version: number; value: Value; timestamp: number; author: string | null };
+export type Crdt = ({ kind: "Text" } & Rga) | ({ kind: "Map" } & OrMap);
+export type CrdtOp = { op: "Insert"; id: OpId; after: OpId | null; value: string } | { op: "Delete"; id: OpId } | { op: "Put"; key: string; value: any; tag: OpId; removes: OpId[] } | { op: "Remove"; key: string; tags: OpId[] };
+export type OpId = { counter: number; site: string };
+export type Rga = { elements: RgaElement[]; pending: CrdtOp[] };
+export type RgaElement = { id: OpId; value: string; deleted: boolean };
+export type OrMap = { entries: { [key: string]: MapEntry[] }; removed: OpId[] };
+export type MapEntry = { tag: OpId; value: any };
+export type Protocol = "json" | "msgpack" | "cbor";
 
 export type ClientMessageTypes = ClientMessage["type"];
 export type ServerMessageTypes = ServerMessage["type"];
 export type ServerMessageMap<T extends ServerMessageTypes> = Omit<Extract<ServerMessage, { type: T }>, 'type'>;
 export type ClientMessageMap<T extends ClientMessageTypes> = Omit<Extract<ClientMessage, { type: T }>, 'type'>;
+// text frames are always json, binary frames are in the protocol the socket negotiated
+export const PROTOCOLS: Protocol[] = ["json", "msgpack", "cbor"];
+export type Codec = { protocol: Protocol; encode: (message: ClientMessage) => string | Uint8Array; decode: (data: string | ArrayBuffer) => ServerMessage };
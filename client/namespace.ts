@@ -1,23 +1,50 @@
 import { writable } from "svelte/store";
 import { browser } from "$app/environment";
 
-import type { ClientMessage, ClientMessageMap, ClientMessageTypes, ServerMessage } from "./messages";
+import type { ClientMessage, ClientMessageMap, ClientMessageTypes, Codec, CrdtOp, PatchOp, Revision, ServerMessage, ServerMessageMap, StoreKind } from "./messages";
+import { apply_merge, apply_patch } from "./patch";
+import { Replica } from "./crdt";
 
 export class Namespace {
     public name: string;
     public write_key: string | null = null;
     public stringify: boolean = true;
+    // encodes messages in a binary protocol instead of json, e.g. with a msgpack library
+    public codec: Codec | null = null;
 
     private ws: WebSocket | null = null;
     private handlers: Map<string, (value: any) => void>;
     private initial: Map<string, any> = new Map();
+    private kinds: Map<string, StoreKind> = new Map();
+    // stores that only need their newest value while the connection is behind
+    private coalesced: Set<string> = new Set();
+    private replicas: Map<string, Replica> = new Map();
+    private site: string = crypto.randomUUID();
+    private versions: Map<string, number> = new Map();
+    // stores waiting for the reply to their Subscribe, which is applied whatever its version
+    private resubscribed: Set<string> = new Set();
+    private values: Map<string, any> = new Map();
+
+    // requests waiting for their Ack, keyed by message id
+    private pending: Map<number, { resolve: (version: number) => void, reject: (error: Error) => void }> = new Map();
+    private next_id = 1;
+    // History replies arrive right before their Ack
+    private revisions: Map<string, Revision[]> = new Map();
+
+    // called when a compare_and_set lost against another writer
+    public onconflict: ((store_name: string, version: number) => void) | null = null;
+    // called when a token passed to `authenticate` ran out, pass a fresh one to `authenticate` again
+    public ontokenexpired: (() => void) | null = null;
+    // called when the server rejected a message
+    public onerror: ((error: ServerMessageMap<'Error'>) => void) | null = null;
 
     private ready = false;
 
-    constructor(name: string, write_key: string | null = null, stringify: boolean = true) {
+    constructor(name: string, write_key: string | null = null, stringify: boolean = true, codec: Codec | null = null) {
         this.name = name;
         this.write_key = write_key;
         this.stringify = stringify;
+        this.codec = codec;
 
         this.handlers = new Map();
 
@@ -28,8 +55,11 @@ export class Namespace {
     }
 
     private connect() {
-        const write = this.write_key ? `/${this.write_key}` : '';
-        this.ws = new WebSocket(`ws://wsl:3000/ws/${this.name}${write}`);
+        // the write key is sent in an Auth message, never in the url
+        this.ws = this.codec
+            ? new WebSocket(`ws://wsl:3000/ws/${this.name}`, [this.codec.protocol])
+            : new WebSocket(`ws://wsl:3000/ws/${this.name}`);
+        this.ws.binaryType = 'arraybuffer';
 
         this.hook_ws();
     }
@@ -60,14 +90,24 @@ export class Namespace {
 
     private onopen() {
         this.ready = true;
+        if (this.write_key) {
+            this.send_message('Auth', { token: this.write_key });
+        }
         this.handlers.forEach((_, store_name) => {
             const initial = this.initial.get(store_name);
-            this.send_message('Subscribe', { store: store_name, initial: this.stringifix(initial)});
+            const kind = this.kinds.get(store_name) ?? 'Text';
+            // after a reconnect only the updates that were missed are sent
+            const last_seen_version = this.versions.get(store_name) ?? null;
+            const coalesce = this.coalesced.has(store_name);
+            this.resubscribed.add(store_name);
+            this.send_message('Subscribe', { store: store_name, initial: this.initial_value(kind, initial), kind, last_seen_version, coalesce });
         });
     }
 
     private onclose() {
         this.ready = false;
+        this.pending.forEach(({ reject }) => reject(new Error('connection closed')));
+        this.pending.clear();
         // try to reconnect
         setTimeout(() => {
             this.connect();
@@ -76,12 +116,96 @@ export class Namespace {
 
     private onmessage(event: MessageEvent) {
         try {
-            const msg: ServerMessage = JSON.parse(event.data);
+            // the server falls back to json if it does not speak the codec's protocol
+            const msg: ServerMessage = this.codec && this.ws?.protocol === this.codec.protocol
+                ? this.codec.decode(event.data)
+                : JSON.parse(event.data);
             switch (msg.type) {
                 case 'Update':
-                    const value = this.stringify ? JSON.parse(msg.value) : msg.value;
+                    // updates can arrive out of order, never go back to an older version. the
+                    // reply to a Subscribe is the exception: a server without a data directory
+                    // starts its versions over after a restart, and so does a store it dropped
+                    if (!this.resubscribed.delete(msg.store) && msg.version < (this.versions.get(msg.store) ?? 0)) {
+                        break;
+                    }
+                    this.versions.set(msg.store, msg.version);
+
+                    // blobs are passed on as { content_type, data } with the data as bytes
+                    const value = typeof msg.value !== 'string'
+                        ? { content_type: msg.value.content_type, data: this.bytes(msg.value.data) }
+                        : this.stringify ? JSON.parse(msg.value) : msg.value;
+                    this.values.set(msg.store, value);
                     this.handlers.get(msg.store)?.(value);
                     break;
+                case 'Patch':
+                case 'Merge': {
+                    const current = this.versions.get(msg.store) ?? 0;
+                    if (msg.version <= current) {
+                        break;
+                    }
+
+                    // a patch only applies on top of the version right before it, otherwise refetch the whole value
+                    if (msg.version !== current + 1 || !this.values.has(msg.store)) {
+                        this.get(msg.store).catch(console.error);
+                        break;
+                    }
+
+                    const doc = this.stringify ? this.values.get(msg.store) : JSON.parse(this.values.get(msg.store));
+                    const patched = msg.type === 'Patch' ? apply_patch(doc, msg.ops) : apply_merge(doc, msg.patch);
+                    const value = this.stringify ? patched : JSON.stringify(patched);
+
+                    this.versions.set(msg.store, msg.version);
+                    this.values.set(msg.store, value);
+                    this.handlers.get(msg.store)?.(value);
+                    break;
+                }
+                case 'State': {
+                    this.resubscribed.delete(msg.store);
+                    const replica = new Replica(msg.state, this.site);
+                    this.replicas.set(msg.store, replica);
+                    this.versions.set(msg.store, msg.version);
+                    this.values.set(msg.store, replica.value());
+                    this.handlers.get(msg.store)?.(replica.value());
+                    break;
+                }
+                case 'Ops': {
+                    // operations commute, so they can be applied in any order
+                    const replica = this.replicas.get(msg.store);
+                    if (!replica) {
+                        break;
+                    }
+                    replica.apply(msg.ops);
+                    this.versions.set(msg.store, Math.max(msg.version, this.versions.get(msg.store) ?? 0));
+                    this.values.set(msg.store, replica.value());
+                    this.handlers.get(msg.store)?.(replica.value());
+                    break;
+                }
+                case 'History':
+                    this.revisions.set(msg.store, msg.revisions);
+                    break;
+                case 'Ack':
+                    this.pending.get(msg.id)?.resolve(msg.version);
+                    this.pending.delete(msg.id);
+                    break;
+                case 'Conflict':
+                    if (msg.request_id !== null) {
+                        this.pending.get(msg.request_id)?.reject(new Error(`conflict, store is at version ${msg.version}`));
+                        this.pending.delete(msg.request_id);
+                    }
+                    this.onconflict?.(msg.store, msg.version);
+                    break;
+                case 'Error':
+                    if (msg.code === 'TokenExpired' && this.ontokenexpired) {
+                        this.ontokenexpired();
+                    } else if (msg.request_id !== null && this.pending.has(msg.request_id)) {
+                        this.pending.get(msg.request_id)?.reject(new Error(`${msg.code}: ${msg.message}`));
+                        this.pending.delete(msg.request_id);
+                    } else if (this.onerror) {
+                        this.onerror(msg);
+                    } else {
+                        console.error(`${msg.code}: ${msg.message}`, msg.store);
+                    }
+                    break;
                 default:
                     console.error('unknown message type');
             }
@@ -90,66 +214,154 @@ export class Namespace {
         }
     }
 
-    private send_message<T extends ClientMessageTypes>(type: T, value: ClientMessageMap<T>) {
+    private send_message<T extends ClientMessageTypes>(type: T, value: Omit<ClientMessageMap<T>, 'id'>, id: number | null = null) {
         if (!this.ws) {
             console.error('no websocket connection');
             return;
         }
 
-        const message: ClientMessage = { type, ...value } as any;
-        const serialized = JSON.stringify(message);
+        const message: ClientMessage = { type, ...value, id } as any;
+        const serialized = this.codec && this.ws.protocol === this.codec.protocol
+            ? this.codec.encode(message)
+            : JSON.stringify(message);
 
         this.ws.send(serialized);
     }
 
-    public set(store_name: string, value: any) {
-        this.send_message('Set', { store: store_name, value: this.stringifix(value)});
+    // sends a message and resolves with the store version once the server acknowledged it
+    private request<T extends ClientMessageTypes>(type: T, value: Omit<ClientMessageMap<T>, 'id'>): Promise<number> {
+        if (!this.ready) {
+            return Promise.reject(new Error('not connected'));
+        }
+
+        const id = this.next_id++;
+        return new Promise((resolve, reject) => {
+            this.pending.set(id, { resolve, reject });
+            this.send_message(type, value, id);
+        });
+    }
+
+    // upgrades the connection to write access without reconnecting
+    public async authenticate(write_key: string): Promise<void> {
+        this.write_key = write_key;
+        await this.request('Auth', { token: write_key });
+    }
+
+    // resolves with the new version of the store
+    public set(store_name: string, value: any): Promise<number> {
+        return this.request('Set', { store: store_name, value: this.stringifix(value)});
+    }
+
+    // only applies the write if the store is still at the version last seen by this client
+    public compare_and_set(store_name: string, value: any): Promise<number> {
+        const expected_version = this.versions.get(store_name) ?? 0;
+        return this.request('CompareAndSet', { store: store_name, expected_version, value: this.stringifix(value)});
     }
 
-    public get(store_name: string) {
-        this.send_message('Get', { store: store_name });
+    // RFC 6902 json patch, only for json stores
+    public patch(store_name: string, ops: PatchOp[], expected_version: number | null = null): Promise<number> {
+        return this.request('Patch', { store: store_name, ops, expected_version });
     }
 
-    private subscribe(store_name: string, initial: any, handler: (value: any) => void) {
+    // RFC 7386 json merge patch, only for json stores
+    public merge(store_name: string, patch: any, expected_version: number | null = null): Promise<number> {
+        return this.request('Merge', { store: store_name, patch, expected_version });
+    }
+
+    // writes raw bytes to a blob store, creating it if needed
+    public set_blob(store_name: string, data: Uint8Array, content_type: string = 'application/octet-stream'): Promise<number> {
+        // json carries the bytes as base64, binary protocols as they are
+        const encoded: any = this.codec && this.ws?.protocol === this.codec.protocol
+            ? data
+            : btoa(Array.from(data, (byte) => String.fromCharCode(byte)).join(''));
+        return this.request('SetBlob', { store: store_name, content_type, data: encoded, expected_version: null });
+    }
+
+    private bytes(data: string | Uint8Array): Uint8Array {
+        if (typeof data !== 'string') {
+            return data;
+        }
+        return Uint8Array.from(atob(data), (c) => c.charCodeAt(0));
+    }
+
+    public version(store_name: string): number {
+        return this.versions.get(store_name) ?? 0;
+    }
+
+    // the Update with the value always arrives before the Ack
+    public async get(store_name: string): Promise<any> {
+        await this.request('Get', { store: store_name });
+        return this.values.get(store_name);
+    }
+
+    // the revisions the server still retains, oldest first
+    public async history(store_name: string, since_version: number | null = null): Promise<Revision[]> {
+        await this.request('History', { store: store_name, since_version });
+        const revisions = this.revisions.get(store_name) ?? [];
+        this.revisions.delete(store_name);
+        return revisions;
+    }
+
+    private subscribe(store_name: string, initial: any, handler: (value: any) => void, kind: StoreKind = 'Text', coalesce: boolean = false) {
         this.handlers.set(store_name, handler);
         this.initial.set(store_name, initial);
+        this.kinds.set(store_name, kind);
+        if (coalesce) {
+            this.coalesced.add(store_name);
+        } else {
+            this.coalesced.delete(store_name);
+        }
         
         if (!this.ready) {
             return;
         }
-        this.send_message('Subscribe', { store: store_name, initial: this.stringifix(initial)});
+        this.resubscribed.add(store_name);
+        this.send_message('Subscribe', { store: store_name, initial: this.initial_value(kind, initial), kind, last_seen_version: null, coalesce });
+    }
+
+    // crdt stores are seeded from a plain string or object, regardless of `stringify`
+    private initial_value(kind: StoreKind, initial: any): string {
+        switch (kind) {
+            case 'CrdtText':
+                return String(initial);
+            case 'CrdtMap':
+                return JSON.stringify(initial);
+            default:
+                return this.stringifix(initial);
+        }
     }
     
-    // create a new readable store
-    public readable<T>(store_name: string, initial: T) {
+    // create a new readable store, `coalesce` skips to the newest value when
+    // the connection falls behind, e.g. for cursors
+    public readable<T>(store_name: string, initial: T, kind: StoreKind = 'Text', coalesce: boolean = false) {
         const store = writable(initial);
         const handle = (value: T) => {
             store.set(value);
         }   
-        this.subscribe(store_name, initial, handle);
+        this.subscribe(store_name, initial, handle, kind, coalesce);
 
         return { subscribe: store.subscribe };
     }
 
     // create a new writable store
-    public writable<T>(store_name: string, initial: T) {
+    public writable<T>(store_name: string, initial: T, kind: StoreKind = 'Text', coalesce: boolean = false) {
         const store = writable(initial);
         const handle = (value: T) => {
             store.set(value);
         }   
-        this.subscribe(store_name, initial, handle);
+        this.subscribe(store_name, initial, handle, kind, coalesce);
 
         return {
             subscribe: store.subscribe,
             set: (value: T) => {
                 if (!this.ready) { return; }
-                this.set(store_name, value);
+                this.set(store_name, value).catch(console.error);
             },
             update: (fn: (value: T) => T) => {
                 store.update((current) => {
                     const value = fn(current);
                     if (!this.ready) { return current }
-                    this.set(store_name, value);
+                    this.set(store_name, value).catch(console.error);
                     return current;
                 });
             },
@@ -157,13 +369,44 @@ export class Namespace {
                 this.send_message('Unsubscribe', { store: store_name });
                 this.handlers.delete(store_name);
                 this.initial.delete(store_name);
+                this.kinds.delete(store_name);
+                this.coalesced.delete(store_name);
+                this.resubscribed.delete(store_name);
             },
             changeto: (store_name: string, initial: T) => {
                 this.send_message('Unsubscribe', { store: store_name });
                 this.handlers.delete(store_name);
                 this.initial.delete(store_name);
-                this.subscribe(store_name, initial, handle);
+                this.subscribe(store_name, initial, handle, kind, coalesce);
+            },
+            patch: (ops: PatchOp[]) => this.patch(store_name, ops),
+            merge: (patch: any) => this.merge(store_name, patch)
+        }
+    }
+
+    // create a collaborative store, edits from every client are merged instead of overwriting each other
+    public crdt<K extends 'CrdtText' | 'CrdtMap'>(store_name: string, kind: K, initial: K extends 'CrdtText' ? string : Record<string, any>) {
+        const store = writable<any>(initial);
+        this.subscribe(store_name, initial, (value: any) => store.set(value), kind);
+
+        const edit = (make: (replica: Replica) => CrdtOp[]) => {
+            const replica = this.replicas.get(store_name);
+            if (!replica || !this.ready) {
+                return;
             }
+
+            const ops = make(replica);
+            replica.apply(ops);
+            store.set(replica.value());
+            this.request('Apply', { store: store_name, ops }).catch(console.error);
+        };
+
+        return {
+            subscribe: store.subscribe,
+            insert: (index: number, text: string) => edit((r) => r.insert(index, text)),
+            delete: (index: number, count: number = 1) => edit((r) => r.delete(index, count)),
+            put: (key: string, value: any) => edit((r) => r.put(key, value)),
+            remove: (key: string) => edit((r) => r.remove(key)),
         }
     }
-}
\ No newline at end of file
+}
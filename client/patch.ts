@@ -0,0 +1,97 @@
+import type { PatchOp } from "./messages";
+
+// applies RFC 6902 and RFC 7386 patches received from the server, returning new documents
+
+function parse_pointer(path: string): string[] {
+    if (path === '') {
+        return [];
+    }
+
+    return path.slice(1).split('/').map((token) => token.replaceAll('~1', '/').replaceAll('~0', '~'));
+}
+
+function clone(value: any): any {
+    return value === undefined ? undefined : JSON.parse(JSON.stringify(value));
+}
+
+function get(doc: any, tokens: string[]): any {
+    return tokens.reduce((node, token) => node?.[token], doc);
+}
+
+function add(doc: any, tokens: string[], value: any): any {
+    if (tokens.length === 0) {
+        return value;
+    }
+
+    const parent = get(doc, tokens.slice(0, -1));
+    const key = tokens[tokens.length - 1];
+    if (Array.isArray(parent)) {
+        parent.splice(key === '-' ? parent.length : Number(key), 0, value);
+    } else {
+        parent[key] = value;
+    }
+
+    return doc;
+}
+
+function remove(doc: any, tokens: string[]): any {
+    const parent = get(doc, tokens.slice(0, -1));
+    const key = tokens[tokens.length - 1];
+    if (Array.isArray(parent)) {
+        parent.splice(Number(key), 1);
+    } else {
+        delete parent[key];
+    }
+
+    return doc;
+}
+
+export function apply_patch(doc: any, ops: PatchOp[]): any {
+    let result = clone(doc);
+
+    for (const op of ops) {
+        const path = parse_pointer(op.path);
+        switch (op.op) {
+            case 'add':
+                result = add(result, path, clone(op.value));
+                break;
+            case 'remove':
+                result = remove(result, path);
+                break;
+            case 'replace':
+                result = add(remove(result, path), path, clone(op.value));
+                break;
+            case 'move': {
+                const from = parse_pointer(op.from);
+                const value = get(result, from);
+                result = add(remove(result, from), path, value);
+                break;
+            }
+            case 'copy':
+                result = add(result, path, clone(get(result, parse_pointer(op.from))));
+                break;
+            case 'test':
+                // the server only broadcasts patches that passed their tests
+                break;
+        }
+    }
+
+    return result;
+}
+
+export function apply_merge(doc: any, patch: any): any {
+    if (patch === null || typeof patch !== 'object' || Array.isArray(patch)) {
+        return clone(patch);
+    }
+
+    const result = doc !== null && typeof doc === 'object' && !Array.isArray(doc) ? clone(doc) : {};
+    for (const [key, value] of Object.entries(patch)) {
+        if (value === null) {
+            delete result[key];
+        } else {
+            result[key] = apply_merge(result[key], value);
+        }
+    }
+
+    return result;
+}
@@ -23,10 +23,18 @@ class SimpleNameSpace {
         return value
     }
 
-    public async get(store: string): Promise<any | null> {
-        const response = await fetch(`${SimpleNameSpace.URL}/read/${this.name}/${store}`);
+    // pass `version` to read an older value, as long as the server still retains it
+    public async get(store: string, version: number | null = null): Promise<any | null> {
+        const query = version !== null ? `?version=${version}` : '';
+        const response = await fetch(`${SimpleNameSpace.URL}/read/${this.name}/${store}${query}`);
 
         if (response.status === 200) {
+            // blob stores are served as the content type they were written with
+            const content_type = response.headers.get('Content-Type') ?? '';
+            if (!content_type.startsWith('text/plain') && !content_type.startsWith('application/json')) {
+                return await response.blob();
+            }
+
             const text = await response.text();
             return this.stringify ? JSON.parse(text) : text;
         }
@@ -34,21 +42,51 @@ class SimpleNameSpace {
         return null;
     }
 
-    public async set(store: string, value: any): Promise<void> {
-        const response = await fetch(`${SimpleNameSpace.URL}/write/${this.name}/${this.write_key}/${store}`, {
+    // pass `expected_version` to only write if the store has not changed since, 0 means the store must not exist yet
+    public async set(store: string, value: any, expected_version: number | null = null): Promise<number> {
+        const headers: Record<string, string> = { 'Authorization': `Bearer ${this.write_key}` };
+        if (expected_version !== null) {
+            headers['If-Match'] = `"${expected_version}"`;
+        }
+        const response = await fetch(`${SimpleNameSpace.URL}/write/${this.name}/${store}`, {
             method: 'POST',
+            headers,
             body: this.stringifix(value)
         });
 
+        if (response.status === 412) {
+            throw new Error('Store was changed by another writer');
+        }
+
+        if (response.status !== 200) {
+            throw new Error('Failed to write to store');
+        }
+
+        return Number(response.headers.get('ETag')?.replaceAll('"', '') ?? 0);
+    }
+
+    // writes a blob, e.g. an image, creating a blob store if needed
+    public async set_blob(store: string, blob: Blob): Promise<number> {
+        const response = await fetch(`${SimpleNameSpace.URL}/write/${this.name}/${store}`, {
+            method: 'POST',
+            headers: {
+                'Authorization': `Bearer ${this.write_key}`,
+                'Content-Type': blob.type || 'application/octet-stream',
+            },
+            body: blob
+        });
+
         if (response.status !== 200) {
             throw new Error('Failed to write to store');
         }
+
+        return Number(response.headers.get('ETag')?.replaceAll('"', '') ?? 0);
     }
 }
 
 
 // return two methods [get, set] that can be used to interact with the namespace
-export function Namespace(name: string, write_key: string | null = null, stringify: boolean = true): [get: (store: string) => Promise<any | null>, set: (store: string, value: any) => Promise<void>] {
+export function Namespace(name: string, write_key: string | null = null, stringify: boolean = true): [get: (store: string, version?: number | null) => Promise<any | null>, set: (store: string, value: any, expected_version?: number | null) => Promise<number>] {
     const ns = new SimpleNameSpace(name, write_key, stringify);
     return [ns.get.bind(ns), ns.set.bind(ns)];
 }
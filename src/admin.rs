@@ -0,0 +1,235 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+
+use crate::{app::App, namespace::access::AccessList};
+
+// every route expects `Authorization: Bearer <admin key>`, the routes of a
+// single namespace also accept a key with the admin role on all of its stores
+pub fn router() -> Router<App> {
+    Router::new()
+        .route("/namespaces", get(list_namespaces).post(create_namespace))
+        .route(
+            "/namespaces/:ns",
+            get(namespace_info).delete(delete_namespace),
+        )
+        .route("/namespaces/:ns/rotate", post(rotate_write_key))
+        .route(
+            "/namespaces/:ns/write_keys",
+            post(add_write_key).delete(remove_write_key),
+        )
+        .route("/namespaces/:ns/access", get(get_access).put(set_access))
+}
+
+#[derive(Deserialize)]
+struct CreateNamespace {
+    name: String,
+    write_key: String,
+    #[serde(default)]
+    access: AccessList,
+}
+
+#[derive(Deserialize)]
+struct RotateWriteKey {
+    write_key: String,
+}
+
+async fn authorize(app: &App, headers: &HeaderMap, peer: IpAddr) -> Result<(), StatusCode> {
+    let Some(token) = crate::bearer(headers) else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+    match app.is_admin(token, peer).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(StatusCode::UNAUTHORIZED),
+        Err(_) => Err(StatusCode::TOO_MANY_REQUESTS),
+    }
+}
+
+async fn authorize_namespace(
+    app: &App,
+    headers: &HeaderMap,
+    ns: &String,
+    peer: IpAddr,
+) -> Result<(), StatusCode> {
+    let Some(token) = crate::bearer(headers) else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+    match app.is_admin(token, peer).await {
+        Ok(true) => return Ok(()),
+        Ok(false) => {}
+        Err(_) => return Err(StatusCode::TOO_MANY_REQUESTS),
+    }
+
+    match app.capability(ns, Some(token), peer).await {
+        Some(Ok(capability)) if capability.is_admin() => Ok(()),
+        Some(Err(_)) => Err(StatusCode::TOO_MANY_REQUESTS),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+fn error_response(e: &'static str) -> Response {
+    match e {
+        "Namespace not found" => StatusCode::NOT_FOUND,
+        "Namespace already exists" => StatusCode::CONFLICT,
+        "Invalid namespace name" => StatusCode::BAD_REQUEST,
+        "Write key not found" => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+    .into_response()
+}
+
+async fn list_namespaces(
+    State(app): State<App>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(e) = authorize(&app, &headers, peer.ip()).await {
+        return e.into_response();
+    }
+
+    Json(app.list_namespaces().await).into_response()
+}
+
+async fn create_namespace(
+    State(app): State<App>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<CreateNamespace>,
+) -> Response {
+    if let Err(e) = authorize(&app, &headers, peer.ip()).await {
+        return e.into_response();
+    }
+
+    match app
+        .create_namespace(body.name.clone(), body.write_key, body.access)
+        .await
+    {
+        Ok(_) => (StatusCode::CREATED, "ok").into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn namespace_info(
+    State(app): State<App>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(ns): Path<String>,
+) -> Response {
+    if let Err(e) = authorize_namespace(&app, &headers, &ns, peer.ip()).await {
+        return e.into_response();
+    }
+
+    match app.namespace_info(&ns).await {
+        Some(info) => Json(info).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn rotate_write_key(
+    State(app): State<App>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(ns): Path<String>,
+    Json(body): Json<RotateWriteKey>,
+) -> Response {
+    if let Err(e) = authorize_namespace(&app, &headers, &ns, peer.ip()).await {
+        return e.into_response();
+    }
+
+    match app.rotate_write_key(&ns, body.write_key).await {
+        Ok(_) => "ok".into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+// the body names the key to add or remove, like rotating
+async fn add_write_key(
+    State(app): State<App>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(ns): Path<String>,
+    Json(body): Json<RotateWriteKey>,
+) -> Response {
+    if let Err(e) = authorize_namespace(&app, &headers, &ns, peer.ip()).await {
+        return e.into_response();
+    }
+
+    match app.add_write_key(&ns, body.write_key).await {
+        Ok(_) => "ok".into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn remove_write_key(
+    State(app): State<App>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(ns): Path<String>,
+    Json(body): Json<RotateWriteKey>,
+) -> Response {
+    if let Err(e) = authorize_namespace(&app, &headers, &ns, peer.ip()).await {
+        return e.into_response();
+    }
+
+    match app.remove_write_key(&ns, body.write_key).await {
+        Ok(_) => "ok".into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn get_access(
+    State(app): State<App>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(ns): Path<String>,
+) -> Response {
+    if let Err(e) = authorize_namespace(&app, &headers, &ns, peer.ip()).await {
+        return e.into_response();
+    }
+
+    match app.access(&ns).await {
+        Some(access) => Json(access).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// replaces the public stores and keys of a namespace
+async fn set_access(
+    State(app): State<App>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(ns): Path<String>,
+    Json(access): Json<AccessList>,
+) -> Response {
+    if let Err(e) = authorize_namespace(&app, &headers, &ns, peer.ip()).await {
+        return e.into_response();
+    }
+
+    match app.set_access(&ns, access).await {
+        Ok(_) => "ok".into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn delete_namespace(
+    State(app): State<App>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(ns): Path<String>,
+) -> Response {
+    if let Err(e) = authorize(&app, &headers, peer.ip()).await {
+        return e.into_response();
+    }
+
+    match app.delete_namespace(&ns).await {
+        Ok(_) => "ok".into_response(),
+        Err(e) => error_response(e),
+    }
+}
@@ -1,58 +1,385 @@
-use axum::extract::ws::WebSocket;
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
 use moka::future::Cache;
 
-use crate::namespace::{Namespace, NamespaceInner};
+use crate::{
+    config::{NamespaceConfig, NamespaceOptions},
+    namespace::{
+        access::{AccessList, Capability},
+        limit::PeerLimits,
+        AuthLimited, Namespace, NamespaceInfo, NamespaceInner, Write, WriteError,
+    },
+    persist::Persistence,
+    secret,
+    store::{Entry, Revision, StoreKind},
+    ws::upgrade::WebSocket,
+};
 
-#[repr(transparent)]
 #[derive(Clone)]
 pub struct App {
     namespaces: Cache<String, Namespace>,
+
+    // hash of the server-wide credential for the admin api, `None` disables it
+    admin_key: Option<String>,
+    // keys recently checked against `admin_key`, by their digest, and how many
+    // keys each client address may have checked per second
+    admin_verified: Cache<[u8; 32], bool>,
+    admin_limits: Arc<PeerLimits>,
+    backend: Option<Persistence>,
+    options: NamespaceOptions,
 }
 
 impl App {
-    pub fn new() -> Self {
+    pub fn new(
+        admin_key: Option<String>,
+        backend: Option<Persistence>,
+        options: NamespaceOptions,
+    ) -> Self {
         Self {
             namespaces: Cache::builder().build(),
+            admin_key: admin_key.map(secret::hashed),
+            admin_verified: Cache::builder()
+                .max_capacity(1024)
+                .time_to_live(Duration::from_secs(600))
+                .build(),
+            admin_limits: Arc::new(PeerLimits::new(options.rate_limits.auth)),
+            backend,
+            options,
+        }
+    }
+
+    // rehydrates every namespace (and its stores) saved in the backend.
+    // namespaces that came from the config file but are no longer in `config`
+    // stay on disk, but are not restored
+    pub async fn restore(&self, config: &[NamespaceConfig]) {
+        let Some(backend) = &self.backend else {
+            return;
+        };
+
+        match backend.load_namespaces().await {
+            Ok(namespaces) => {
+                for (name, record) in namespaces {
+                    if record.configured && !config.iter().any(|ns| ns.name == name) {
+                        info!(
+                            "Not restoring namespace {}, it was removed from the config",
+                            name
+                        );
+                        continue;
+                    }
+                    info!("Restoring namespace: {}", name);
+
+                    // records with cleartext keys are rewritten with their hashes
+                    let cleartext = record.write_key.is_some()
+                        || record.write_keys.iter().any(|key| !secret::is_hash(key))
+                        || record
+                            .access
+                            .keys
+                            .iter()
+                            .any(|key| !secret::is_hash(&key.key));
+
+                    let mut write_keys = record.write_keys;
+                    write_keys.extend(record.write_key);
+                    let namespace = self.new_namespace(name, write_keys, record.access).await;
+                    namespace.set_configured(record.configured);
+                    if cleartext {
+                        namespace.persist().await;
+                    }
+                }
+            }
+            Err(e) => error!("Error restoring namespaces: {}", e),
+        }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.options.max_frame_size
+    }
+
+    // keys that were not checked recently count against the `auth` limit of
+    // the address they came from, like the keys of a namespace
+    pub async fn is_admin(&self, key: &str, peer: IpAddr) -> Result<bool, AuthLimited> {
+        let Some(hash) = self.admin_key.clone() else {
+            return Ok(false);
+        };
+
+        let digest = secret::digest(key);
+        if let Some(admin) = self.admin_verified.get(&digest).await {
+            return Ok(admin);
         }
+        if !self.admin_limits.take(peer, 0).await {
+            return Err(AuthLimited);
+        }
+
+        let key = key.to_owned();
+        let admin = secret::blocking(move || secret::verify(&key, &hash)).await;
+        self.admin_verified.insert(digest, admin).await;
+        Ok(admin)
     }
 
-    pub async fn new_namespace(&self, name: String, write_key: String) -> Namespace {
-        let namespace: std::sync::Arc<NamespaceInner> = NamespaceInner::new(write_key).await;
+    pub async fn new_namespace(
+        &self,
+        name: String,
+        write_keys: Vec<String>,
+        access: AccessList,
+    ) -> Namespace {
+        let namespace: std::sync::Arc<NamespaceInner> = NamespaceInner::new(
+            name.clone(),
+            write_keys,
+            access,
+            self.backend.clone(),
+            self.options,
+        )
+        .await;
         self.namespaces.insert(name, namespace.clone()).await;
         namespace
     }
 
-    // ideally namespaces should be created seperate from connections
+    pub async fn create_namespace(
+        &self,
+        name: String,
+        write_key: String,
+        access: AccessList,
+    ) -> Result<Namespace, &'static str> {
+        if name.is_empty() {
+            return Err("Invalid namespace name");
+        }
+
+        // only one of several concurrent requests for the same name creates it
+        let entry = self
+            .namespaces
+            .entry(name.clone())
+            .or_insert_with(NamespaceInner::new(
+                name,
+                vec![write_key],
+                access,
+                self.backend.clone(),
+                self.options,
+            ))
+            .await;
+        if !entry.is_fresh() {
+            return Err("Namespace already exists");
+        }
+
+        let namespace = entry.into_value();
+        namespace.persist().await;
+        Ok(namespace)
+    }
+
+    // creates the namespaces from the config, or brings the keys of existing
+    // ones in line with it. only what differs from the `previous` config is
+    // applied, so keys changed through the admin api are kept until their
+    // entry in the config changes
+    pub async fn configure(&self, previous: &[NamespaceConfig], namespaces: Vec<NamespaceConfig>) {
+        for config in namespaces {
+            let previous = previous.iter().find(|ns| ns.name == config.name);
+            if previous == Some(&config) {
+                continue;
+            }
+
+            let compression = config.compression.unwrap_or(self.options.compression);
+            match self.namespaces.get(&config.name).await {
+                Some(ns) => {
+                    // e.g. a namespace that was created through the admin api
+                    if ns.set_configured(true) {
+                        ns.persist().await;
+                    }
+                    if previous.is_none_or(|previous| previous.write_keys != config.write_keys) {
+                        ns.set_write_keys(config.write_keys).await;
+                    }
+                    if previous.is_none_or(|previous| previous.access != config.access) {
+                        ns.set_access(config.access).await;
+                    }
+                    ns.set_compression(compression).await;
+                }
+                None => {
+                    info!("Creating namespace: {}", config.name);
+                    let ns = self
+                        .new_namespace(config.name, config.write_keys, config.access)
+                        .await;
+                    ns.set_compression(compression).await;
+                    ns.set_configured(true);
+                    ns.persist().await;
+                }
+            }
+        }
+    }
+
+    // applies a changed config. namespaces that were removed from it are
+    // unloaded and not restored on the next start, their data is only erased
+    // through the admin api
+    pub async fn reconfigure(
+        &self,
+        previous: &[NamespaceConfig],
+        namespaces: Vec<NamespaceConfig>,
+    ) {
+        for config in previous {
+            if namespaces.iter().any(|ns| ns.name == config.name) {
+                continue;
+            }
+
+            warn!(
+                "Unloading namespace {}, it was removed from the config",
+                config.name
+            );
+            let _ = self.unload_namespace(&config.name).await;
+        }
+
+        self.configure(previous, namespaces).await;
+    }
+
+    pub async fn list_namespaces(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .namespaces
+            .iter()
+            .map(|(name, _)| (*name).clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub async fn namespace_info(&self, name: &String) -> Option<NamespaceInfo> {
+        let ns = self.namespaces.get(name).await?;
+        Some(ns.info().await)
+    }
+
+    pub async fn rotate_write_key(
+        &self,
+        name: &String,
+        write_key: String,
+    ) -> Result<(), &'static str> {
+        let Some(ns) = self.namespaces.get(name).await else {
+            return Err("Namespace not found");
+        };
+        ns.set_write_keys(vec![write_key]).await;
+        Ok(())
+    }
+
+    // an additional write key, so clients can move over before the old one is removed
+    pub async fn add_write_key(
+        &self,
+        name: &String,
+        write_key: String,
+    ) -> Result<(), &'static str> {
+        let Some(ns) = self.namespaces.get(name).await else {
+            return Err("Namespace not found");
+        };
+        ns.add_write_key(write_key).await;
+        Ok(())
+    }
+
+    pub async fn remove_write_key(
+        &self,
+        name: &String,
+        write_key: String,
+    ) -> Result<(), &'static str> {
+        let Some(ns) = self.namespaces.get(name).await else {
+            return Err("Namespace not found");
+        };
+        ns.remove_write_key(write_key).await
+    }
+
+    pub async fn access(&self, name: &String) -> Option<AccessList> {
+        let ns = self.namespaces.get(name).await?;
+        Some(ns.access().await)
+    }
+
+    pub async fn set_access(&self, name: &String, access: AccessList) -> Result<(), &'static str> {
+        let Some(ns) = self.namespaces.get(name).await else {
+            return Err("Namespace not found");
+        };
+        ns.set_access(access).await;
+        Ok(())
+    }
+
+    // what `key` may do in the namespace, `None` if the namespace does not exist
+    pub async fn capability(
+        &self,
+        namespace: &String,
+        key: Option<&str>,
+        peer: IpAddr,
+    ) -> Option<Result<Capability, AuthLimited>> {
+        let ns = self.namespaces.get(namespace).await?;
+        Some(ns.capability(key, peer).await)
+    }
+
+    // `None` if the namespace does not exist
+    pub async fn allows_origin(&self, namespace: &String, origin: Option<&str>) -> Option<bool> {
+        let ns = self.namespaces.get(namespace).await?;
+        Some(ns.allows_origin(origin).await)
+    }
+
+    pub async fn delete_namespace(&self, name: &String) -> Result<(), &'static str> {
+        let ns = self.unload_namespace(name).await?;
+        ns.erase().await;
+        Ok(())
+    }
+
+    // disconnects the namespace and drops it from memory, what was persisted
+    // of it is kept and restored on the next start
+    pub async fn unload_namespace(&self, name: &String) -> Result<Namespace, &'static str> {
+        let Some(ns) = self.namespaces.remove(name).await else {
+            return Err("Namespace not found");
+        };
+        ns.shutdown().await;
+        Ok(ns)
+    }
+
     pub async fn add_connection(
         self,
         namespace: String,
         write_key: Option<String>,
+        origin: Option<String>,
+        peer: IpAddr,
         websocket: WebSocket,
     ) {
         let Some(ns) = self.namespaces.get(&namespace).await else {
-            println!("Namespace not found: {}", namespace);
+            debug!("Namespace not found: {}", namespace);
             return;
         };
-        ns.add_connection(websocket, write_key.as_ref()).await;
+        ns.add_connection(websocket, write_key.as_deref(), origin, peer)
+            .await;
     }
 
-    pub async fn read_store(self, namespace: &String, store: &String) -> Option<String> {
-        let Some(ns) = self.namespaces.get(namespace).await else {
-            return None;
-        };
+    pub async fn read_store(
+        self,
+        namespace: &String,
+        store: &String,
+    ) -> Option<(Entry, StoreKind)> {
+        let ns = self.namespaces.get(namespace).await?;
         ns.read_store(store).await
     }
 
+    pub async fn read_version(
+        self,
+        namespace: &String,
+        store: &String,
+        version: u64,
+    ) -> Option<Option<(Entry, StoreKind)>> {
+        let ns = self.namespaces.get(namespace).await?;
+        ns.read_version(store, version).await
+    }
+
+    pub async fn history(
+        self,
+        namespace: &String,
+        store: &String,
+        since_version: u64,
+    ) -> Option<Vec<Revision>> {
+        let ns = self.namespaces.get(namespace).await?;
+        ns.history(store, since_version).await
+    }
+
     pub async fn write_store(
         self,
         namespace: &String,
-        write_key: &String,
+        write_key: &str,
         store: &String,
-        value: String,
-    ) -> Result<(), &'static str> {
+        write: Write,
+        size: usize,
+        peer: IpAddr,
+    ) -> Result<u64, WriteError> {
         let Some(ns) = self.namespaces.get(namespace).await else {
-            return Err("Namespace not found");
+            return Err(WriteError::NamespaceNotFound);
         };
-        ns.write_store(store, write_key, value).await
+        ns.write_store(store, write_key, write, size, peer).await
     }
 }
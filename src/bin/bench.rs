@@ -0,0 +1,166 @@
+// measures how many writes per second the server handles with more cores,
+// by starting it with 1, 2, 4, ... threads and writing to many stores at once.
+// build with `--release`, e.g. `cargo run --release --bin bench`
+
+mod common;
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use tokio_tungstenite::tungstenite::Message;
+
+#[derive(Parser, Debug)]
+#[command(about = "Measures write throughput of the store server per number of threads")]
+struct Args {
+    #[arg(long, help = "Server binary [default: `store` next to this binary]")]
+    server: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Thread counts to run the server with [default: 1, 2, 4, ... up to the cores]"
+    )]
+    threads: Vec<usize>,
+
+    #[arg(long, default_value_t = 64, help = "Sockets writing at the same time")]
+    sockets: usize,
+
+    #[arg(long, default_value_t = 64, help = "Stores the sockets write to")]
+    stores: usize,
+
+    #[arg(
+        long,
+        default_value_t = 16,
+        help = "Writes every socket keeps in flight"
+    )]
+    window: usize,
+
+    #[arg(
+        long,
+        default_value_t = 5,
+        help = "Seconds to measure every thread count"
+    )]
+    seconds: u64,
+
+    #[arg(long, default_value_t = 3099)]
+    port: u16,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let server = args.server.clone().unwrap_or_else(common::default_server);
+
+    let threads = if args.threads.is_empty() {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let mut threads: Vec<usize> = std::iter::successors(Some(1), |n| Some(n * 2))
+            .take_while(|n| *n < cores)
+            .collect();
+        threads.push(cores);
+        threads
+    } else {
+        args.threads.clone()
+    };
+
+    println!(
+        "{} sockets writing to {} stores, {} writes in flight each",
+        args.sockets, args.stores, args.window
+    );
+    println!("{:>8} {:>14}", "threads", "writes/s");
+    for threads in threads {
+        let mut child = common::start_server(&server, args.port, threads);
+        let writes = run(&args).await;
+        let _ = child.kill();
+        let _ = child.wait();
+
+        println!("{:>8} {:>14.0}", threads, writes);
+    }
+}
+
+// writes as fast as the server acknowledges, returns the writes per second
+async fn run(args: &Args) -> f64 {
+    let acked = Arc::new(AtomicU64::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let mut tasks = Vec::new();
+    for socket in 0..args.sockets {
+        let store = format!("store-{}", socket % args.stores);
+        tasks.push(tokio::task::spawn(write(
+            args.port,
+            store,
+            args.window,
+            acked.clone(),
+            done.clone(),
+        )));
+    }
+
+    // the first second is left out, while sockets connect
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let start = acked.load(Ordering::Relaxed);
+    let started = Instant::now();
+    tokio::time::sleep(Duration::from_secs(args.seconds)).await;
+    let writes = acked.load(Ordering::Relaxed) - start;
+    let elapsed = started.elapsed();
+
+    done.store(true, Ordering::Relaxed);
+    for task in tasks {
+        let _ = task.await;
+    }
+
+    writes as f64 / elapsed.as_secs_f64()
+}
+
+async fn write(
+    port: u16,
+    store: String,
+    window: usize,
+    acked: Arc<AtomicU64>,
+    done: Arc<AtomicBool>,
+) {
+    let socket = common::connect(port, true).await;
+    let (mut sink, mut stream) = socket.split();
+
+    let set = |id: u64| {
+        let message =
+            json!({ "type": "Set", "store": store, "value": id.to_string(), "id": id as u32 });
+        Message::Text(message.to_string())
+    };
+
+    let mut next = 0;
+    for _ in 0..window {
+        next += 1;
+        if sink.send(set(next)).await.is_err() {
+            return;
+        }
+    }
+
+    while let Some(Ok(message)) = stream.next().await {
+        let Message::Text(text) = message else {
+            continue;
+        };
+        if !text.contains(r#""type":"Ack""#) {
+            continue;
+        }
+
+        acked.fetch_add(1, Ordering::Relaxed);
+        if done.load(Ordering::Relaxed) {
+            break;
+        }
+
+        next += 1;
+        if sink.send(set(next)).await.is_err() {
+            break;
+        }
+    }
+
+    let _ = sink.close().await;
+}
@@ -0,0 +1,83 @@
+// shared by the bench and loadtest binaries, which start a server of their
+// own to measure
+
+use std::{
+    net::TcpStream,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use tokio::net::TcpStream as AsyncTcpStream;
+use tokio_tungstenite::{tungstenite::client::IntoClientRequest, MaybeTlsStream, WebSocketStream};
+
+pub const NAMESPACE: &str = "bench";
+pub const WRITE_KEY: &str = "bench";
+
+pub type Connection = WebSocketStream<MaybeTlsStream<AsyncTcpStream>>;
+
+// the `store` binary next to the running one
+pub fn default_server() -> PathBuf {
+    let exe = std::env::current_exe().expect("failed to find the running binary");
+    exe.with_file_name("store")
+}
+
+// starts the server without rate limits on `port`, returns once it listens
+pub fn start_server(server: &Path, port: u16, threads: usize) -> Child {
+    let config = std::env::temp_dir().join(format!("store-bench-{}.toml", std::process::id()));
+    let text = format!(
+        r#"
+bind = "127.0.0.1:{port}"
+log_level = "error"
+legacy_routes = false
+
+[limits.rate]
+socket = {{}}
+key = {{}}
+namespace = {{}}
+
+[queues]
+workers = {threads}
+
+[[namespaces]]
+name = "{NAMESPACE}"
+write_keys = ["{WRITE_KEY}"]
+"#,
+    );
+    std::fs::write(&config, text).expect("failed to write the bench config");
+
+    let child = Command::new(server)
+        .arg("--config")
+        .arg(&config)
+        .env("TOKIO_WORKER_THREADS", threads.to_string())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|e| panic!("failed to start {}: {}", server.display(), e));
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        if Instant::now() > deadline {
+            panic!("server did not start listening");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    child
+}
+
+// opens a socket to the bench namespace, with the write key if `authorize`.
+// without it the socket can only read, but skips hashing the key
+pub async fn connect(port: u16, authorize: bool) -> Connection {
+    let url = format!("ws://127.0.0.1:{}/ws/{}", port, NAMESPACE);
+    let mut request = url.into_client_request().expect("invalid url");
+    if authorize {
+        let bearer = format!("Bearer {}", WRITE_KEY)
+            .parse()
+            .expect("invalid key");
+        request.headers_mut().insert("Authorization", bearer);
+    }
+
+    let (socket, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .expect("failed to connect");
+    socket
+}
@@ -0,0 +1,204 @@
+// measures how many updates per second the server fans out, by subscribing
+// many sockets to a single store and writing to it as fast as it acknowledges.
+// build with `--release`, e.g. `cargo run --release --bin loadtest`
+
+mod common;
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use futures::{
+    stream::{self, SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use serde_json::json;
+use tokio_tungstenite::tungstenite::Message;
+
+const STORE: &str = "fanout";
+
+#[derive(Parser, Debug)]
+#[command(about = "Measures how many updates per second the server delivers to subscribers of one store")]
+struct Args {
+    #[arg(long, help = "Server binary [default: `store` next to this binary]")]
+    server: Option<PathBuf>,
+
+    #[arg(long, help = "Threads to run the server with [default: the cores]")]
+    threads: Option<usize>,
+
+    #[arg(long, default_value_t = 10000, help = "Sockets subscribed to the store")]
+    subscribers: usize,
+
+    #[arg(long, default_value_t = 4, help = "Writes the writer keeps in flight")]
+    window: usize,
+
+    #[arg(long, default_value_t = 64, help = "Bytes in every written value")]
+    size: usize,
+
+    #[arg(long, default_value_t = 5, help = "Seconds to measure")]
+    seconds: u64,
+
+    #[arg(long, default_value_t = 3098)]
+    port: u16,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let server = args.server.clone().unwrap_or_else(common::default_server);
+    let threads = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+
+    let mut child = common::start_server(&server, args.port, threads);
+
+    // the store is created by the writer, subscribers without a key can only
+    // subscribe to stores that exist
+    let writer = common::connect(args.port, true).await;
+    let (mut sink, mut stream) = writer.split();
+    sink.send(set(0, args.size))
+        .await
+        .expect("failed to create the store");
+    while let Some(Ok(message)) = stream.next().await {
+        if matches!(message, Message::Text(text) if text.contains(r#""type":"Ack""#)) {
+            break;
+        }
+    }
+
+    // connects a few sockets at a time, so the listen backlog does not overflow
+    let started = Instant::now();
+    let subscribed = Arc::new(AtomicU64::new(0));
+    let delivered = Arc::new(AtomicU64::new(0));
+    let subscribers: Vec<_> = stream::iter(0..args.subscribers)
+        .map(|_| subscribe(args.port, subscribed.clone(), delivered.clone()))
+        .buffer_unordered(256)
+        .collect()
+        .await;
+    while subscribed.load(Ordering::Relaxed) < args.subscribers as u64 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    println!(
+        "{} sockets subscribed to one store in {:.1}s, server on {} threads",
+        args.subscribers,
+        started.elapsed().as_secs_f64(),
+        threads
+    );
+
+    let acked = Arc::new(AtomicU64::new(0));
+    let writer = tokio::task::spawn(write(
+        sink,
+        stream,
+        args.window,
+        args.size,
+        acked.clone(),
+    ));
+
+    // the first second is left out, while the writer gets going
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let (writes, updates) = (
+        acked.load(Ordering::Relaxed),
+        delivered.load(Ordering::Relaxed),
+    );
+    let started = Instant::now();
+    tokio::time::sleep(Duration::from_secs(args.seconds)).await;
+    let writes = acked.load(Ordering::Relaxed) - writes;
+    let updates = delivered.load(Ordering::Relaxed) - updates;
+    let elapsed = started.elapsed().as_secs_f64();
+
+    writer.abort();
+    for subscriber in subscribers {
+        subscriber.abort();
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+
+    let expected = writes * args.subscribers as u64;
+    println!("{:>14} {:>14} {:>10}", "writes/s", "updates/s", "delivered");
+    println!(
+        "{:>14.0} {:>14.0} {:>9.1}%",
+        writes as f64 / elapsed,
+        updates as f64 / elapsed,
+        // below 100% when queues of sockets that fell behind were coalesced
+        if expected == 0 {
+            0.0
+        } else {
+            updates as f64 * 100.0 / expected as f64
+        }
+    );
+}
+
+// connects and subscribes a socket without a key, then counts its updates in
+// the background
+async fn subscribe(
+    port: u16,
+    subscribed: Arc<AtomicU64>,
+    delivered: Arc<AtomicU64>,
+) -> tokio::task::JoinHandle<()> {
+    let socket = common::connect(port, false).await;
+    let (mut sink, mut stream) = socket.split();
+
+    let message = json!({ "type": "Subscribe", "store": STORE, "initial": "", "id": 1 });
+    sink.send(Message::Text(message.to_string()))
+        .await
+        .expect("failed to subscribe");
+
+    tokio::task::spawn(async move {
+        let _sink = sink;
+        let mut acked = false;
+        while let Some(Ok(message)) = stream.next().await {
+            let Message::Text(text) = message else {
+                continue;
+            };
+            if !acked && text.contains(r#""type":"Ack""#) {
+                acked = true;
+                subscribed.fetch_add(1, Ordering::Relaxed);
+            } else if text.contains(r#""type":"Update""#) {
+                delivered.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    })
+}
+
+// a value of `size` bytes, starting with the id so every write changes it
+fn set(id: u64, size: usize) -> Message {
+    let value = format!("{:010}{}", id, "x".repeat(size.saturating_sub(10)));
+    let message = json!({ "type": "Set", "store": STORE, "value": value, "id": id as u32 });
+    Message::Text(message.to_string())
+}
+
+async fn write(
+    mut sink: SplitSink<common::Connection, Message>,
+    mut stream: SplitStream<common::Connection>,
+    window: usize,
+    size: usize,
+    acked: Arc<AtomicU64>,
+) {
+    let mut next = 0;
+    for _ in 0..window {
+        next += 1;
+        if sink.send(set(next, size)).await.is_err() {
+            return;
+        }
+    }
+
+    while let Some(Ok(message)) = stream.next().await {
+        let Message::Text(text) = message else {
+            continue;
+        };
+        if !text.contains(r#""type":"Ack""#) {
+            continue;
+        }
+
+        acked.fetch_add(1, Ordering::Relaxed);
+        next += 1;
+        if sink.send(set(next, size)).await.is_err() {
+            break;
+        }
+    }
+}
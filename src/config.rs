@@ -0,0 +1,376 @@
+use std::{collections::HashSet, net::SocketAddr, path::PathBuf, time::Duration};
+
+use axum::http::HeaderValue;
+use clap::Parser;
+use serde::Deserialize;
+
+use crate::{
+    log::Level,
+    namespace::{access::AccessList, limit::RateLimits},
+    persist::Compression,
+    ws::socket::Overflow,
+};
+
+// flags override the config file, which overrides the defaults
+#[derive(Parser, Clone, Debug)]
+#[command(version, about = "A websocket key value store")]
+pub struct Cli {
+    #[arg(short, long, env = "STORE_CONFIG", help = "Path to a toml config file")]
+    pub config: Option<PathBuf>,
+
+    #[arg(long, help = "Address to listen on [default: 0.0.0.0:3002]")]
+    pub bind: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        env = "ADMIN_KEY",
+        help = "Key for the admin api, or its argon2 hash"
+    )]
+    pub admin_key: Option<String>,
+
+    #[arg(
+        long,
+        env = "DATA_DIR",
+        help = "Directory to persist namespaces and stores to"
+    )]
+    pub data_dir: Option<PathBuf>,
+
+    #[arg(long, help = "Least severe messages to log [default: info]")]
+    pub log_level: Option<Level>,
+
+    #[arg(
+        long = "cors-origin",
+        help = "Allowed origin, may be repeated, `*` allows any"
+    )]
+    pub cors_origins: Vec<String>,
+
+    #[arg(long, help = "Seconds a store stays in memory after its last use")]
+    pub store_idle: Option<u64>,
+
+    #[arg(long, help = "Largest message in bytes a socket may send")]
+    pub max_message_size: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Tasks handling each namespace's messages [default: cores]"
+    )]
+    pub workers: Option<usize>,
+
+    #[arg(
+        long,
+        env = "LEGACY_ROUTES",
+        help = "Serve the routes with write keys in the path"
+    )]
+    pub legacy_routes: Option<bool>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub admin_key: Option<String>,
+    pub data_dir: Option<PathBuf>,
+    pub log_level: Level,
+    // serve the deprecated routes that take the write key in the path
+    pub legacy_routes: bool,
+    pub cors: Cors,
+    pub stores: Stores,
+    pub limits: Limits,
+    pub queues: Queues,
+    // how values are compressed when they are persisted, unless a namespace
+    // sets its own
+    pub compression: Compression,
+    pub namespaces: Vec<NamespaceConfig>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cors {
+    pub origins: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Stores {
+    // seconds
+    pub idle: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    // bytes
+    pub max_message_size: usize,
+    // websocket frames larger than this close the connection, bytes
+    pub max_frame_size: usize,
+    // bytes
+    pub max_value_size: usize,
+    // per namespace
+    pub max_stores: usize,
+    pub max_store_name: usize,
+    // bytes of values and history a namespace keeps in memory, the least
+    // recently used stores are evicted beyond this
+    pub max_memory: u64,
+    pub rate: RateLimits,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Queues {
+    // messages from sockets waiting to be handled, per namespace
+    pub inbound: usize,
+    // messages waiting to be written, per socket
+    pub outbound: usize,
+    // what to do once a socket's outbound queue is full
+    pub overflow: Overflow,
+    // tasks handling the messages of each namespace, every store is
+    // handled by one of them. defaults to the number of cores
+    pub workers: usize,
+}
+
+// namespaces that are created on startup, keys may be given as argon2 hashes
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamespaceConfig {
+    pub name: String,
+    pub write_keys: Vec<String>,
+    // an environment variable whose value, when set, replaces `write_keys`
+    pub write_key_env: Option<String>,
+    #[serde(default)]
+    pub access: AccessList,
+    pub compression: Option<Compression>,
+}
+
+// what every namespace is created with
+#[derive(Clone, Copy, Debug)]
+pub struct NamespaceOptions {
+    pub store_idle: Duration,
+    pub max_message_size: usize,
+    pub max_frame_size: usize,
+    pub max_value_size: usize,
+    pub max_stores: usize,
+    pub max_store_name: usize,
+    pub max_memory: u64,
+    pub rate_limits: RateLimits,
+    pub inbound_queue: usize,
+    pub outbound_queue: usize,
+    pub overflow: Overflow,
+    pub workers: usize,
+    pub compression: Compression,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 3002)),
+            admin_key: None,
+            data_dir: None,
+            log_level: Level::Info,
+            legacy_routes: true,
+            cors: Cors::default(),
+            stores: Stores::default(),
+            limits: Limits::default(),
+            queues: Queues::default(),
+            compression: Compression::default(),
+            namespaces: Vec::new(),
+        }
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            origins: vec!["*".to_owned()],
+        }
+    }
+}
+
+impl Default for Stores {
+    fn default() -> Self {
+        Self { idle: 3600 * 12 }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_message_size: 4 * 1024 * 1024,
+            max_frame_size: 16 * 1024 * 1024,
+            max_value_size: 4 * 1024 * 1024,
+            max_stores: 10_000,
+            max_store_name: 256,
+            max_memory: 256 * 1024 * 1024,
+            rate: RateLimits::default(),
+        }
+    }
+}
+
+impl Default for Queues {
+    fn default() -> Self {
+        Self {
+            inbound: 1024,
+            outbound: 256,
+            overflow: Overflow::default(),
+            workers: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(cli: Cli) -> Result<Self, String> {
+        let mut config = match &cli.config {
+            Some(path) => Self::read(path)?,
+            None => Self::default(),
+        };
+
+        if let Some(bind) = cli.bind {
+            config.bind = bind;
+        }
+        if cli.admin_key.is_some() {
+            config.admin_key = cli.admin_key;
+        }
+        if cli.data_dir.is_some() {
+            config.data_dir = cli.data_dir;
+        }
+        if let Some(level) = cli.log_level {
+            config.log_level = level;
+        }
+        if !cli.cors_origins.is_empty() {
+            config.cors.origins = cli.cors_origins;
+        }
+        if let Some(idle) = cli.store_idle {
+            config.stores.idle = idle;
+        }
+        if let Some(size) = cli.max_message_size {
+            config.limits.max_message_size = size;
+        }
+        if let Some(workers) = cli.workers {
+            config.queues.workers = workers;
+        }
+        if let Some(legacy_routes) = cli.legacy_routes {
+            config.legacy_routes = legacy_routes;
+        }
+        for namespace in &mut config.namespaces {
+            let key = namespace
+                .write_key_env
+                .as_ref()
+                .and_then(|var| std::env::var(var).ok());
+            if let Some(key) = key {
+                namespace.write_keys = vec![key];
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn read(path: &PathBuf) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("invalid config {}: {}", path.display(), e))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.stores.idle == 0 {
+            return Err("stores.idle must be at least 1 second".into());
+        }
+        let limits = [
+            (
+                "limits.max_message_size",
+                self.limits.max_message_size as u64,
+            ),
+            ("limits.max_frame_size", self.limits.max_frame_size as u64),
+            ("limits.max_value_size", self.limits.max_value_size as u64),
+            ("limits.max_stores", self.limits.max_stores as u64),
+            ("limits.max_store_name", self.limits.max_store_name as u64),
+            ("limits.max_memory", self.limits.max_memory),
+            ("queues.inbound", self.queues.inbound as u64),
+            ("queues.outbound", self.queues.outbound as u64),
+            ("queues.workers", self.queues.workers as u64),
+        ];
+        for (name, limit) in limits {
+            if limit == 0 {
+                return Err(format!("{} must be at least 1", name));
+            }
+        }
+
+        if self.limits.max_value_size as u64 > self.limits.max_memory {
+            return Err("limits.max_value_size must not be larger than limits.max_memory".into());
+        }
+
+        validate_compression("compression", &self.compression)?;
+
+        for origin in &self.cors.origins {
+            if origin != "*" && HeaderValue::from_str(origin).is_err() {
+                return Err(format!("cors origin `{}` is not a valid origin", origin));
+            }
+        }
+
+        let mut names = HashSet::new();
+        for namespace in &self.namespaces {
+            if namespace.name.is_empty() {
+                return Err("namespaces need a name".into());
+            }
+            if !names.insert(&namespace.name) {
+                return Err(format!("namespace `{}` is defined twice", namespace.name));
+            }
+            if namespace.write_keys.iter().any(String::is_empty) {
+                return Err(format!(
+                    "namespace `{}` has an empty write key",
+                    namespace.name
+                ));
+            }
+            if let Some(compression) = &namespace.compression {
+                validate_compression(&format!("namespace `{}`", namespace.name), compression)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // whether the settings that are only read on startup differ
+    pub fn needs_restart(&self, other: &Config) -> bool {
+        self.bind != other.bind
+            || self.admin_key != other.admin_key
+            || self.data_dir != other.data_dir
+            || self.legacy_routes != other.legacy_routes
+            || self.cors != other.cors
+            || self.stores != other.stores
+            || self.limits != other.limits
+            || self.queues != other.queues
+            || self.compression != other.compression
+    }
+
+    pub fn namespace_options(&self) -> NamespaceOptions {
+        NamespaceOptions {
+            store_idle: Duration::from_secs(self.stores.idle),
+            max_message_size: self.limits.max_message_size,
+            max_frame_size: self.limits.max_frame_size,
+            max_value_size: self.limits.max_value_size,
+            max_stores: self.limits.max_stores,
+            max_store_name: self.limits.max_store_name,
+            max_memory: self.limits.max_memory,
+            rate_limits: self.limits.rate,
+            inbound_queue: self.queues.inbound,
+            outbound_queue: self.queues.outbound,
+            overflow: self.queues.overflow,
+            workers: self.queues.workers,
+            compression: self.compression,
+        }
+    }
+}
+
+fn validate_compression(name: &str, compression: &Compression) -> Result<(), String> {
+    let levels = zstd::compression_level_range();
+    if !levels.contains(&compression.level) {
+        return Err(format!(
+            "{} compression level must be between {} and {}",
+            name,
+            levels.start(),
+            levels.end()
+        ));
+    }
+    Ok(())
+}
@@ -1,18 +1,49 @@
 #![feature(try_blocks)]
 
 use axum::{
-    extract::{Path, State, WebSocketUpgrade},
-    http::StatusCode,
+    body::Bytes,
+    extract::{ConnectInfo, DefaultBodyLimit, Path, Query, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH, ORIGIN},
+        HeaderMap, HeaderValue, StatusCode,
+    },
     response::{IntoResponse, Response},
     routing::{get, post},
-    Router,
+    Json, Router,
+};
+use clap::Parser;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+
+use crate::{
+    app::App,
+    config::{Cli, Config},
+    namespace::{messages::export_types, Change, Write, WriteError},
+    persist::{DiskBackend, Persistence},
+    store::{
+        blob::{self, OCTET_STREAM},
+        json::JsonChange,
+        StoreKind, Value,
+    },
+    ws::{codec::Protocol, upgrade::WebSocketUpgrade},
 };
-use tower_http::cors::CorsLayer;
 
-use crate::{app::App, namespace::messages::export_types};
+#[macro_use]
+pub mod log;
 
+pub mod admin;
 pub mod app;
+pub mod config;
 pub mod namespace;
+pub mod persist;
+pub mod reload;
+pub mod secret;
 pub mod store;
 pub mod ws;
 
@@ -21,91 +52,422 @@ async fn main() {
     #[cfg(debug_assertions)]
     export_types("./client/messages.ts");
 
-    let app = App::new();
-
-    app.new_namespace(
-        String::from("nathan"),
-        std::env::var("NATHAN_WRITE_KEY").unwrap_or("soup".into()),
-    )
-    .await;
-    app.new_namespace(
-        String::from("sarah"),
-        std::env::var("SARAH_WRITE_KEY").unwrap_or("rock".into()),
-    )
-    .await;
-    app.new_namespace(
-        String::from("sandbox"),
-        String::from(""), // no write key
-    )
-    .await;
-    app.new_namespace(String::from("fetch-cache"), String::from("wk"))
-        .await;
-    app.new_namespace(
-        String::from("tictactoe"),
-        String::from("x"), // no write key
-    )
-    .await;
-
-    let cors = CorsLayer::permissive();
-    let router = Router::new()
+    let cli = Cli::parse();
+    let config = match Config::load(cli.clone()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+    };
+    log::set_level(config.log_level);
+
+    let backend: Option<Persistence> = match &config.data_dir {
+        Some(dir) => Some(Arc::new(
+            DiskBackend::open(dir)
+                .await
+                .expect("failed to open data directory"),
+        )),
+        None => None,
+    };
+
+    let app = App::new(
+        config.admin_key.clone(),
+        backend,
+        config.namespace_options(),
+    );
+    app.restore(&config.namespaces).await;
+    app.configure(&[], config.namespaces.clone()).await;
+    reload::watch(app.clone(), cli, config.clone());
+
+    let cors = cors(&config.cors.origins);
+    let mut router = Router::new()
         .route("/", get(root))
         .route("/read/:ns/:store", get(read_store))
-        .route("/write/:ns/:wk/:store", post(write_store))
-        .route("/ws/:ns", get(handle_ws_read))
-        .route("/ws/:ns/:wp", get(handle_ws_write))
+        .route("/history/:ns/:store", get(store_history))
+        .route("/write/:ns/:store", post(write_store).patch(patch_store))
+        .route("/ws/:ns", get(handle_ws));
+
+    // write keys in the path end up in proxy logs and browser history, these
+    // routes are only kept around for older clients
+    if config.legacy_routes {
+        warn!("Serving deprecated write key routes, set legacy_routes = false to disable them");
+        router = router
+            .route(
+                "/write/:ns/:wk/:store",
+                post(legacy_write_store).patch(legacy_patch_store),
+            )
+            .route("/ws/:ns/:wp", get(legacy_handle_ws));
+    }
+
+    // axum stops reading bodies at 2 MiB by default, requests up to the size of
+    // a value have to get through to be checked against it
+    let router = router
+        .nest("/admin", admin::router())
+        .layer(DefaultBodyLimit::max(config.limits.max_value_size))
         .layer(cors)
         .with_state(app);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3002")
+    let listener = tokio::net::TcpListener::bind(config.bind)
         .await
-        .expect("failed to bind to port");
+        .expect("failed to bind to address");
 
-    println!("Listening on 3002");
+    info!("Listening on {}", config.bind);
 
-    axum::serve(listener, router).await.expect("server failed");
+    // the address of the client limits how often it may try keys
+    let service = router.into_make_service_with_connect_info::<SocketAddr>();
+    axum::serve(listener, service).await.expect("server failed");
+}
+
+// `*` allows every origin, origins were validated when the config was loaded.
+// namespaces can narrow this down further with their own `origins`
+fn cors(origins: &[String]) -> CorsLayer {
+    if origins.iter().any(|origin| origin == "*") {
+        return CorsLayer::permissive();
+    }
+
+    let origins: Vec<HeaderValue> = origins
+        .iter()
+        .filter_map(|origin| HeaderValue::from_str(origin).ok())
+        .collect();
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods(Any)
+        .allow_headers(Any)
+        .expose_headers(Any)
 }
 
 async fn root() -> &'static str {
     "Hello, World!"
 }
 
-async fn read_store(State(app): State<App>, Path((ns, store)): Path<(String, String)>) -> Response {
-    match app.read_store(&ns, &store).await {
-        Some(data) => data.into_response(),
+#[derive(Deserialize)]
+struct ReadQuery {
+    version: Option<u64>,
+}
+
+// `?version=` reads an older value, as long as it is still in the history
+async fn read_store(
+    State(app): State<App>,
+    Path((ns, store)): Path<(String, String)>,
+    Query(query): Query<ReadQuery>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(status) = authorize_read(&app, &ns, &store, &headers, peer.ip()).await {
+        return status.into_response();
+    }
+
+    let entry = match query.version {
+        Some(version) => match app.read_version(&ns, &store, version).await {
+            Some(Some(entry)) => Some(entry),
+            Some(None) => return StatusCode::GONE.into_response(),
+            None => None,
+        },
+        None => app.read_store(&ns, &store).await,
+    };
+
+    // blobs are served as the content type they were written with
+    match entry {
+        Some((entry, kind)) => {
+            let content_type = entry.value.content_type(kind).to_owned();
+            (
+                [(ETAG, etag(entry.version)), (CONTENT_TYPE, content_type)],
+                entry.value.into_bytes(),
+            )
+                .into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    since: Option<u64>,
+}
+
+async fn store_history(
+    State(app): State<App>,
+    Path((ns, store)): Path<(String, String)>,
+    Query(query): Query<HistoryQuery>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(status) = authorize_read(&app, &ns, &store, &headers, peer.ip()).await {
+        return status.into_response();
+    }
+
+    match app
+        .history(&ns, &store, query.since.unwrap_or_default())
+        .await
+    {
+        Some(revisions) => Json(revisions).into_response(),
         None => StatusCode::NOT_FOUND.into_response(),
     }
 }
 
+// public stores can be read without a key, others need `Authorization: Bearer <key>`
+async fn authorize_read(
+    app: &App,
+    ns: &String,
+    store: &str,
+    headers: &HeaderMap,
+    peer: IpAddr,
+) -> Result<(), StatusCode> {
+    authorize_origin(app, ns, headers).await?;
+    match app.capability(ns, bearer(headers), peer).await {
+        Some(Ok(capability)) if capability.can_read(store) => Ok(()),
+        Some(Ok(_)) => Err(StatusCode::FORBIDDEN),
+        Some(Err(_)) => Err(StatusCode::TOO_MANY_REQUESTS),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+// browsers send an `Origin` header, which has to be allowed by the namespace
+async fn authorize_origin(app: &App, ns: &String, headers: &HeaderMap) -> Result<(), StatusCode> {
+    match app.allows_origin(ns, origin(headers)).await {
+        Some(true) => Ok(()),
+        Some(false) => Err(StatusCode::FORBIDDEN),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+// the write key is passed as `Authorization: Bearer <write key>`
 async fn write_store(
+    State(app): State<App>,
+    Path((ns, store)): Path<(String, String)>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Some(wk) = bearer(&headers).map(str::to_owned) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    write_value(app, ns, wk, store, peer.ip(), headers, body).await
+}
+
+async fn legacy_write_store(
     State(app): State<App>,
     Path((ns, wk, store)): Path<(String, String, String)>,
-    value: String,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Response {
-    match app.write_store(&ns, &wk, &store, value).await {
-        Ok(_) => "ok".into_response(),
-        Err(e) => match e {
-            "Invalid write key" => StatusCode::FORBIDDEN,
-            "Namespace not found" => StatusCode::NOT_FOUND,
-            _ => StatusCode::INTERNAL_SERVER_ERROR,
+    write_value(app, ns, wk, store, peer.ip(), headers, body).await
+}
+
+async fn write_value(
+    app: App,
+    ns: String,
+    wk: String,
+    store: String,
+    peer: IpAddr,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let size = body.len();
+    if let Err(status) = authorize_origin(&app, &ns, &headers).await {
+        return status.into_response();
+    }
+
+    let expected_version = match headers.get(IF_MATCH).map(parse_etag) {
+        Some(Some(version)) => Some(version),
+        Some(None) => return StatusCode::BAD_REQUEST.into_response(),
+        None => None,
+    };
+
+    // plain text is kept as text, anything else as a blob of its content
+    // type. new stores written as json are created as json stores, and as
+    // blob stores when written as a blob
+    let (value, kind) = match content_type(&headers) {
+        None | Some("application/x-www-form-urlencoded") => (text(body), StoreKind::Text),
+        Some("text/plain") => (text(body), StoreKind::Text),
+        Some("application/json") => (text(body), StoreKind::Json),
+        Some(_) => {
+            let content_type = headers[CONTENT_TYPE].to_str().unwrap_or(OCTET_STREAM);
+            let value = Value::Blob {
+                content_type: content_type.to_owned(),
+                data: blob::Bytes(body.to_vec()),
+            };
+            (Some(value), StoreKind::Blob)
         }
-        .into_response(),
+    };
+    let Some(value) = value else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let write = Write {
+        change: Change::Value(value),
+        expected_version,
+        kind,
+        author: "http".into(),
+    };
+    let result = app.write_store(&ns, &wk, &store, write, size, peer).await;
+    write_response(result)
+}
+
+async fn patch_store(
+    State(app): State<App>,
+    Path((ns, store)): Path<(String, String)>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    let Some(wk) = bearer(&headers).map(str::to_owned) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    write_patch(app, ns, wk, store, peer.ip(), headers, body).await
+}
+
+async fn legacy_patch_store(
+    State(app): State<App>,
+    Path((ns, wk, store)): Path<(String, String, String)>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    write_patch(app, ns, wk, store, peer.ip(), headers, body).await
+}
+
+// accepts a json patch, or a merge patch if sent as `application/merge-patch+json`
+async fn write_patch(
+    app: App,
+    ns: String,
+    wk: String,
+    store: String,
+    peer: IpAddr,
+    headers: HeaderMap,
+    body: String,
+) -> Response {
+    let size = body.len();
+    if let Err(status) = authorize_origin(&app, &ns, &headers).await {
+        return status.into_response();
     }
+
+    let expected_version = match headers.get(IF_MATCH).map(parse_etag) {
+        Some(Some(version)) => Some(version),
+        Some(None) => return StatusCode::BAD_REQUEST.into_response(),
+        None => None,
+    };
+
+    let change = match content_type(&headers) {
+        Some("application/merge-patch+json") => serde_json::from_str(&body).map(JsonChange::Merge),
+        _ => serde_json::from_str(&body).map(JsonChange::Patch),
+    };
+    let Ok(change) = change else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let write = Write {
+        change: Change::Json(change),
+        expected_version,
+        kind: StoreKind::Json,
+        author: "http".into(),
+    };
+    let result = app.write_store(&ns, &wk, &store, write, size, peer).await;
+    write_response(result)
+}
+
+fn write_response(result: Result<u64, WriteError>) -> Response {
+    match result {
+        Ok(version) => ([(ETAG, etag(version))], "ok").into_response(),
+        Err(e) => match e {
+            WriteError::InvalidWriteKey => StatusCode::FORBIDDEN.into_response(),
+            WriteError::RateLimited => StatusCode::TOO_MANY_REQUESTS.into_response(),
+            WriteError::TooLarge(limit) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(json!({ "error": "too large", "limit": limit })),
+            )
+                .into_response(),
+            WriteError::TooManyStores => StatusCode::INSUFFICIENT_STORAGE.into_response(),
+            WriteError::Persist => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            WriteError::NamespaceNotFound | WriteError::StoreNotFound => {
+                StatusCode::NOT_FOUND.into_response()
+            }
+            WriteError::Conflict { version } => (
+                StatusCode::PRECONDITION_FAILED,
+                [(ETAG, etag(version))],
+                Json(json!({ "error": "conflict", "version": version })),
+            )
+                .into_response(),
+            WriteError::Invalid(message) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(json!({ "error": "invalid", "message": message })),
+            )
+                .into_response(),
+        },
+    }
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    value.strip_prefix("Bearer ")
+}
+
+fn origin(headers: &HeaderMap) -> Option<&str> {
+    headers.get(ORIGIN)?.to_str().ok()
+}
+
+// `None` if the body is not utf-8
+fn text(body: Bytes) -> Option<Value> {
+    String::from_utf8(body.to_vec()).ok().map(Value::Text)
 }
 
-async fn handle_ws_read(
+fn content_type(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    value.split(';').next().map(str::trim)
+}
+
+// store versions double as etags, e.g. `"3"`
+fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+fn parse_etag(value: &HeaderValue) -> Option<u64> {
+    let value = value.to_str().ok()?.trim();
+    let value = value.strip_prefix("W/").unwrap_or(value);
+    value.trim_matches('"').parse().ok()
+}
+
+// sockets start out read only unless they send `Authorization: Bearer <write
+// key>`, browsers can't set headers and send an `Auth` message instead
+async fn handle_ws(
     ws: WebSocketUpgrade,
     State(app): State<App>,
     Path(ns): Path<String>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
 ) -> Response {
-    println!("Handling ws connection to namespace: {}", ns);
-    ws.on_upgrade(|socket| app.add_connection(ns, None, socket))
+    if let Err(status) = authorize_origin(&app, &ns, &headers).await {
+        return status.into_response();
+    }
+
+    let wk = bearer(&headers).map(str::to_owned);
+    let origin = origin(&headers).map(str::to_owned);
+    debug!("Handling ws connection to namespace: {}", ns);
+    let size = app.max_frame_size();
+    ws.max_frame_size(size)
+        .max_message_size(size)
+        .protocols(Protocol::ALL.map(Protocol::name))
+        .on_upgrade(move |socket| app.add_connection(ns, wk, origin, peer.ip(), socket))
 }
 
-async fn handle_ws_write(
+async fn legacy_handle_ws(
     ws: WebSocketUpgrade,
     State(app): State<App>,
     Path((ns, wp)): Path<(String, String)>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
 ) -> Response {
-    println!("Handling ws connection to namespace: {}", ns);
-    ws.on_upgrade(move |socket| app.add_connection(ns, Some(wp), socket))
+    if let Err(status) = authorize_origin(&app, &ns, &headers).await {
+        return status.into_response();
+    }
+
+    let origin = origin(&headers).map(str::to_owned);
+    debug!("Handling ws connection to namespace: {}", ns);
+    let size = app.max_frame_size();
+    ws.max_frame_size(size)
+        .max_message_size(size)
+        .protocols(Protocol::ALL.map(Protocol::name))
+        .on_upgrade(move |socket| app.add_connection(ns, Some(wp), origin, peer.ip(), socket))
 }
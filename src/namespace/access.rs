@@ -0,0 +1,384 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use wildmatch::WildMatch;
+
+use crate::secret;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Read,
+    Write,
+    // write access that also allows managing the namespace through the admin api
+    Admin,
+}
+
+// grants `role` on every store whose name matches the glob `stores`, e.g. `game/*`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    pub stores: String,
+    pub role: Role,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Key {
+    // the argon2 hash of the key once it was stored
+    pub key: String,
+    pub rules: Vec<Rule>,
+}
+
+// who may do what in a namespace, on top of the namespace's write key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessList {
+    // globs of the stores anyone can read without a key
+    #[serde(default = "everything")]
+    pub public: Vec<String>,
+    #[serde(default)]
+    pub keys: Vec<Key>,
+    // secret for HS256 signed tokens, tokens are not accepted without it
+    #[serde(default)]
+    pub token_secret: Option<String>,
+    // globs of the origins browsers may use the namespace from, e.g.
+    // `https://*.example.com`. requests without an `Origin` header are not affected
+    #[serde(default = "everything")]
+    pub origins: Vec<String>,
+}
+
+impl Default for AccessList {
+    fn default() -> Self {
+        Self {
+            public: everything(),
+            keys: Vec::new(),
+            token_secret: None,
+            origins: everything(),
+        }
+    }
+}
+
+// the claims of a signed token, `exp` is in seconds since the unix epoch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub ns: String,
+    pub rules: Vec<Rule>,
+    pub exp: u64,
+}
+
+fn everything() -> Vec<String> {
+    vec!["*".to_owned()]
+}
+
+impl AccessList {
+    pub fn public(&self) -> Capability {
+        let rules = self
+            .public
+            .iter()
+            .map(|stores| Rule {
+                stores: stores.clone(),
+                role: Role::Read,
+            })
+            .collect();
+        Capability::new(rules, Source::Public, None)
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.origins
+            .iter()
+            .any(|allowed| WildMatch::new(allowed).matches(origin))
+    }
+
+    // replaces every key with its hash, reusing the hashes of `previous` for
+    // keys that did not change. keys that already are hashes are kept
+    pub fn hashed(mut self, previous: &AccessList) -> Self {
+        let hashes: Vec<String> = previous.keys.iter().map(|k| k.key.clone()).collect();
+        for key in &mut self.keys {
+            key.key = secret::rehashed(std::mem::take(&mut key.key), &hashes);
+        }
+        self
+    }
+
+    // the capability granted by `key`, `None` if no key matches. this hashes
+    // `key` for every key in the list, so it should not run on an async worker
+    pub fn grants(&self, key: &str) -> Option<Capability> {
+        let mut capability = Capability::default();
+        for k in self.keys.iter().filter(|k| secret::verify(key, &k.key)) {
+            let source = Source::Key(k.key.clone());
+            capability.extend(Capability::new(k.rules.clone(), source, None));
+        }
+
+        if capability.grants.is_empty() {
+            return None;
+        }
+        Some(capability)
+    }
+
+    // the capability granted by a signed token for `namespace`, until it expires
+    pub fn verify(&self, namespace: &str, token: &str) -> Option<Capability> {
+        let secret = self.token_secret.as_ref()?;
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        let key = DecodingKey::from_secret(secret.as_bytes());
+        let claims = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .ok()?
+            .claims;
+
+        if claims.ns != namespace {
+            return None;
+        }
+        Some(Capability::new(
+            claims.rules,
+            Source::Token,
+            Some(claims.exp),
+        ))
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// what a socket or request is allowed to do, the highest role of all
+// unexpired rules matching a store applies to it
+#[derive(Clone, Debug, Default)]
+pub struct Capability {
+    grants: Vec<Grant>,
+}
+
+#[derive(Clone, Debug)]
+struct Grant {
+    rule: Rule,
+    source: Source,
+    expires: Option<u64>,
+}
+
+// what a grant came from, so it can be taken away once that is removed. keys
+// are identified by their hash
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    Public,
+    WriteKey(String),
+    Key(String),
+    Token,
+}
+
+impl Capability {
+    pub fn new(rules: Vec<Rule>, source: Source, expires: Option<u64>) -> Self {
+        let grants = rules
+            .into_iter()
+            .map(|rule| Grant {
+                rule,
+                source: source.clone(),
+                expires,
+            })
+            .collect();
+        Self { grants }
+    }
+
+    // `role` on every store
+    pub fn everything(role: Role, source: Source) -> Self {
+        let rule = Rule {
+            stores: "*".to_owned(),
+            role,
+        };
+        Self::new(vec![rule], source, None)
+    }
+
+    // a rule granted again by the same source is only kept once, with the
+    // later expiry, so authenticating repeatedly doesn't pile up grants
+    pub fn extend(&mut self, other: Capability) {
+        for grant in other.grants {
+            let existing = self
+                .grants
+                .iter_mut()
+                .find(|g| g.source == grant.source && g.rule == grant.rule);
+            match existing {
+                Some(existing) => {
+                    existing.expires = match (existing.expires, grant.expires) {
+                        (Some(a), Some(b)) => Some(a.max(b)),
+                        _ => None,
+                    };
+                }
+                None => self.grants.push(grant),
+            }
+        }
+    }
+
+    // the public rules are swapped out instead of revoked
+    pub fn replace_public(&mut self, public: Capability) {
+        self.grants.retain(|grant| grant.source != Source::Public);
+        self.extend(public);
+    }
+
+    // drops the grants whose source no longer grants their rule, returns
+    // whether any were dropped
+    pub fn revoke(&mut self, valid: impl Fn(&Source, &Rule) -> bool) -> bool {
+        let before = self.grants.len();
+        self.grants
+            .retain(|grant| valid(&grant.source, &grant.rule));
+        self.grants.len() != before
+    }
+
+    // the hashes of the keys the grants came from
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = Vec::new();
+        for grant in &self.grants {
+            if let Source::WriteKey(hash) | Source::Key(hash) = &grant.source {
+                if !keys.contains(hash) {
+                    keys.push(hash.clone());
+                }
+            }
+        }
+        keys
+    }
+
+    // when the next of the rules expires
+    pub fn expires(&self) -> Option<u64> {
+        self.grants.iter().filter_map(|grant| grant.expires).min()
+    }
+
+    pub fn forget_expired(&mut self) {
+        let now = now();
+        self.grants
+            .retain(|grant| grant.expires.is_none_or(|expires| expires > now));
+    }
+
+    fn rules(&self) -> impl Iterator<Item = &Rule> {
+        let now = now();
+        self.grants
+            .iter()
+            .filter(move |grant| grant.expires.is_none_or(|expires| expires > now))
+            .map(|grant| &grant.rule)
+    }
+
+    pub fn role(&self, store: &str) -> Option<Role> {
+        self.rules()
+            .filter(|rule| WildMatch::new(&rule.stores).matches(store))
+            .map(|rule| rule.role)
+            .max()
+    }
+
+    pub fn can_read(&self, store: &str) -> bool {
+        self.role(store).is_some()
+    }
+
+    pub fn can_write(&self, store: &str) -> bool {
+        self.role(store) >= Some(Role::Write)
+    }
+
+    // only an admin rule covering every store makes a namespace admin
+    pub fn is_admin(&self) -> bool {
+        self.rules()
+            .any(|rule| rule.role == Role::Admin && rule.stores == "*")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(stores: &str, role: Role) -> Rule {
+        Rule {
+            stores: stores.to_owned(),
+            role,
+        }
+    }
+
+    fn key(hash: &str) -> Source {
+        Source::Key(hash.to_owned())
+    }
+
+    fn token(secret: &str, ns: &str, exp: u64) -> String {
+        let claims = Claims {
+            ns: ns.to_owned(),
+            rules: vec![rule("*", Role::Write)],
+            exp,
+        };
+        let key = jsonwebtoken::EncodingKey::from_secret(secret.as_bytes());
+        jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap()
+    }
+
+    fn signed(secret: &str) -> AccessList {
+        AccessList {
+            token_secret: Some(secret.to_owned()),
+            ..AccessList::default()
+        }
+    }
+
+    #[test]
+    fn rules_match_store_globs() {
+        let capability = Capability::new(vec![rule("game/*", Role::Read)], key("a"), None);
+        assert!(capability.can_read("game/board"));
+        assert!(capability.can_read("game/board/1"));
+        assert!(!capability.can_read("games"));
+        assert!(!capability.can_read("chat"));
+    }
+
+    #[test]
+    fn the_highest_matching_role_applies() {
+        let rules = vec![
+            rule("*", Role::Read),
+            rule("game/*", Role::Write),
+            rule("game/config", Role::Admin),
+        ];
+        let capability = Capability::new(rules, key("a"), None);
+        assert_eq!(capability.role("chat"), Some(Role::Read));
+        assert_eq!(capability.role("game/board"), Some(Role::Write));
+        assert_eq!(capability.role("game/config"), Some(Role::Admin));
+        assert!(!capability.can_write("chat"));
+        assert!(capability.can_write("game/config"));
+
+        // admin on a single store doesn't make a namespace admin
+        assert!(!capability.is_admin());
+        assert!(Capability::everything(Role::Admin, key("a")).is_admin());
+    }
+
+    #[test]
+    fn extend_keeps_a_rule_of_a_source_once() {
+        let mut capability = Capability::new(vec![rule("*", Role::Read)], key("a"), Some(10));
+        capability.extend(Capability::new(
+            vec![rule("*", Role::Read)],
+            key("a"),
+            Some(20),
+        ));
+        assert_eq!(capability.grants.len(), 1);
+        assert_eq!(capability.expires(), Some(20));
+
+        // the same rule from another key is kept apart, so it can be revoked on its own
+        capability.extend(Capability::new(vec![rule("*", Role::Read)], key("b"), None));
+        assert_eq!(capability.grants.len(), 2);
+        assert_eq!(capability.keys(), vec!["a".to_owned(), "b".to_owned()]);
+    }
+
+    #[test]
+    fn verify_accepts_tokens_for_the_namespace() {
+        let exp = now() + 60;
+        let capability = signed("secret").verify("ns", &token("secret", "ns", exp));
+        let capability = capability.unwrap();
+        assert!(capability.can_write("board"));
+        assert_eq!(capability.expires(), Some(exp));
+    }
+
+    #[test]
+    fn verify_rejects_tokens_for_another_namespace() {
+        let token = token("secret", "other", now() + 60);
+        assert!(signed("secret").verify("ns", &token).is_none());
+    }
+
+    #[test]
+    fn verify_rejects_expired_tokens() {
+        let token = token("secret", "ns", now() - 1);
+        assert!(signed("secret").verify("ns", &token).is_none());
+    }
+
+    #[test]
+    fn verify_rejects_tokens_signed_with_another_secret() {
+        let token = token("other", "ns", now() + 60);
+        assert!(signed("secret").verify("ns", &token).is_none());
+        assert!(AccessList::default().verify("ns", &token).is_none());
+    }
+}
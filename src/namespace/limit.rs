@@ -0,0 +1,257 @@
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use moka::future::Cache;
+use serde::Deserialize;
+
+// how much may be sent per second, 0 means unlimited
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    pub messages: u32,
+    pub bytes: u64,
+}
+
+// the limits of every socket, every key and every namespace, a message has to
+// fit all of them
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    pub socket: RateLimit,
+    pub key: RateLimit,
+    pub namespace: RateLimit,
+    // keys per second a namespace checks against its hashes for each client
+    // address, only keys that were not checked recently count. every check
+    // hashes the key once per key of the namespace, so this bounds how much
+    // time a client can make the server spend in argon2
+    pub auth: RateLimit,
+    // rejected messages within a minute before a socket is disconnected, 0 never disconnects
+    pub strikes: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            socket: RateLimit {
+                messages: 100,
+                bytes: 1024 * 1024,
+            },
+            key: RateLimit {
+                messages: 500,
+                bytes: 8 * 1024 * 1024,
+            },
+            namespace: RateLimit {
+                messages: 2000,
+                bytes: 32 * 1024 * 1024,
+            },
+            auth: RateLimit {
+                messages: 20,
+                bytes: 0,
+            },
+            strikes: 50,
+        }
+    }
+}
+
+// a token bucket holding up to one second worth of messages and bytes
+pub struct Bucket {
+    limit: RateLimit,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    messages: f64,
+    bytes: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    pub fn new(limit: RateLimit) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            state: Mutex::new(BucketState {
+                messages: limit.messages as f64,
+                bytes: limit.bytes as f64,
+                refilled: Instant::now(),
+            }),
+        })
+    }
+
+    // takes a message of `len` bytes if there is room for it. bytes can go
+    // into debt, so messages larger than a second's worth still get through
+    pub fn take(&self, len: usize) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.refilled).as_secs_f64();
+        state.refilled = now;
+
+        let messages = self.limit.messages as f64;
+        let bytes = self.limit.bytes as f64;
+        state.messages = (state.messages + elapsed * messages).min(messages);
+        state.bytes = (state.bytes + elapsed * bytes).min(bytes);
+
+        if self.limit.messages > 0 && state.messages < 1.0 {
+            return false;
+        }
+        if self.limit.bytes > 0 && state.bytes <= 0.0 {
+            return false;
+        }
+
+        state.messages -= 1.0;
+        state.bytes -= len as f64;
+        true
+    }
+
+    // gives back a message `take` let through, when a later limit refused it
+    pub fn refund(&self, len: usize) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.messages = (state.messages + 1.0).min(self.limit.messages as f64);
+        state.bytes = (state.bytes + len as f64).min(self.limit.bytes as f64);
+    }
+}
+
+// takes a message from every bucket or from none of them, returns the index
+// of the bucket that refused it
+pub fn take_all(buckets: &[&Bucket], len: usize) -> Result<(), usize> {
+    for (i, bucket) in buckets.iter().enumerate() {
+        if !bucket.take(len) {
+            for taken in &buckets[..i] {
+                taken.refund(len);
+            }
+            return Err(i);
+        }
+    }
+    Ok(())
+}
+
+// a bucket for every client address, so a client running into the limit
+// doesn't use up what the others may do
+pub struct PeerLimits {
+    limit: RateLimit,
+    buckets: Cache<IpAddr, Arc<Bucket>>,
+}
+
+impl PeerLimits {
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            // buckets refill within a second, an idle one is full and can go
+            buckets: Cache::builder()
+                .time_to_idle(Duration::from_secs(60))
+                .build(),
+        }
+    }
+
+    pub async fn take(&self, peer: IpAddr, len: usize) -> bool {
+        let bucket = self
+            .buckets
+            .get_with(peer, async { Bucket::new(self.limit) })
+            .await;
+        bucket.take(len)
+    }
+}
+
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+
+// counts rejected messages, the count starts over every minute
+pub struct Strikes {
+    limit: u32,
+    state: Mutex<(u32, Instant)>,
+}
+
+impl Strikes {
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            state: Mutex::new((0, Instant::now())),
+        }
+    }
+
+    // adds a strike, returns whether the limit was exceeded
+    pub fn strike(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (count, since) = &mut *state;
+        if since.elapsed() > STRIKE_WINDOW {
+            *count = 0;
+            *since = Instant::now();
+        }
+
+        *count += 1;
+        self.limit > 0 && *count > self.limit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(messages: u32, bytes: u64) -> RateLimit {
+        RateLimit { messages, bytes }
+    }
+
+    #[test]
+    fn bucket_holds_a_second_of_messages() {
+        let bucket = Bucket::new(limit(2, 0));
+        assert!(bucket.take(0));
+        assert!(bucket.take(0));
+        assert!(!bucket.take(0));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let bucket = Bucket::new(limit(100, 0));
+        while bucket.take(0) {}
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(bucket.take(0));
+    }
+
+    #[test]
+    fn bucket_lets_large_messages_into_debt() {
+        let bucket = Bucket::new(limit(0, 10));
+        assert!(bucket.take(100));
+        assert!(!bucket.take(1));
+    }
+
+    #[test]
+    fn bucket_without_limits_takes_everything() {
+        let bucket = Bucket::new(limit(0, 0));
+        for _ in 0..1000 {
+            assert!(bucket.take(usize::MAX / 2));
+        }
+    }
+
+    #[test]
+    fn take_all_refunds_buckets_before_the_one_refusing() {
+        let socket = Bucket::new(limit(2, 0));
+        let namespace = Bucket::new(limit(1, 0));
+        assert_eq!(take_all(&[&socket, &namespace], 0), Ok(()));
+        assert_eq!(take_all(&[&socket, &namespace], 0), Err(1));
+
+        // the socket's second message was given back
+        assert!(socket.take(0));
+        assert!(!socket.take(0));
+    }
+
+    #[test]
+    fn refund_does_not_overfill() {
+        let bucket = Bucket::new(limit(1, 0));
+        bucket.refund(0);
+        assert!(bucket.take(0));
+        assert!(!bucket.take(0));
+    }
+
+    #[test]
+    fn strikes_disconnect_past_the_limit() {
+        let strikes = Strikes::new(2);
+        assert!(!strikes.strike());
+        assert!(!strikes.strike());
+        assert!(strikes.strike());
+
+        let unlimited = Strikes::new(0);
+        assert!((0..100).all(|_| !unlimited.strike()));
+    }
+}
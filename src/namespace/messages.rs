@@ -1,29 +1,234 @@
 use std::io::Write;
 
 use serde::{Deserialize, Serialize};
-use specta::Type;
+use serde_json::Value;
+use specta::{
+    ts::{BigIntExportBehavior, ExportConfiguration},
+    Type,
+};
 
+use crate::{
+    store::{
+        blob::Bytes,
+        crdt::{Crdt, CrdtOp, MapEntry, OpId, OrMap, Rga, RgaElement},
+        json::PatchOp,
+        Revision, StoreKind, Value as StoreValue,
+    },
+    ws::codec::Protocol,
+};
+
+// every message can carry an `id`, which is echoed back in the `Ack` or
+// `Error` that answers it
 #[derive(Type, Clone, Debug, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
-    Set { store: String, value: String },
-    Get { store: String },
+    // adds whatever `token` grants to the socket's access
+    Auth {
+        token: String,
+        id: Option<u32>,
+    },
+    Set {
+        store: String,
+        value: String,
+        id: Option<u32>,
+    },
+    CompareAndSet {
+        store: String,
+        expected_version: u64,
+        value: String,
+        id: Option<u32>,
+    },
+    // writes bytes to a blob store, creating it if needed. `data` is base64 in
+    // json and binary in msgpack or cbor
+    SetBlob {
+        store: String,
+        content_type: Option<String>,
+        #[specta(type = String)]
+        data: Bytes,
+        expected_version: Option<u64>,
+        id: Option<u32>,
+    },
+    Get {
+        store: String,
+        id: Option<u32>,
+    },
+    // asks for the retained revisions newer than `since_version`
+    History {
+        store: String,
+        since_version: Option<u64>,
+        id: Option<u32>,
+    },
+
+    // patches operate on json stores only
+    Patch {
+        store: String,
+        ops: Vec<PatchOp>,
+        expected_version: Option<u64>,
+        id: Option<u32>,
+    },
+    Merge {
+        store: String,
+        patch: Value,
+        expected_version: Option<u64>,
+        id: Option<u32>,
+    },
+
+    // operations for crdt stores
+    Apply {
+        store: String,
+        ops: Vec<CrdtOp>,
+        id: Option<u32>,
+    },
+
+    // `kind` is only used if the store does not exist yet, it defaults to `Text`.
+    // with `last_seen_version` only the updates after it are sent, or the
+    // current value if they are no longer retained
+    Subscribe {
+        store: String,
+        initial: String,
+        kind: Option<StoreKind>,
+        last_seen_version: Option<u64>,
+        // while the socket is behind, only send the newest value of the store
+        // instead of every update, e.g. for cursors
+        coalesce: Option<bool>,
+        id: Option<u32>,
+    },
+    Unsubscribe {
+        store: String,
+        id: Option<u32>,
+    },
+}
+
+impl ClientMessage {
+    pub fn id(&self) -> Option<u32> {
+        match self {
+            ClientMessage::Auth { id, .. }
+            | ClientMessage::Set { id, .. }
+            | ClientMessage::CompareAndSet { id, .. }
+            | ClientMessage::SetBlob { id, .. }
+            | ClientMessage::Get { id, .. }
+            | ClientMessage::History { id, .. }
+            | ClientMessage::Patch { id, .. }
+            | ClientMessage::Merge { id, .. }
+            | ClientMessage::Apply { id, .. }
+            | ClientMessage::Subscribe { id, .. }
+            | ClientMessage::Unsubscribe { id, .. } => *id,
+        }
+    }
 
-    Subscribe { store: String, initial: String },
-    Unsubscribe { store: String },
+    // the store the message is about, `Auth` is about the whole socket
+    pub fn store(&self) -> Option<&str> {
+        match self {
+            ClientMessage::Auth { .. } => None,
+            ClientMessage::Set { store, .. }
+            | ClientMessage::CompareAndSet { store, .. }
+            | ClientMessage::SetBlob { store, .. }
+            | ClientMessage::Get { store, .. }
+            | ClientMessage::History { store, .. }
+            | ClientMessage::Patch { store, .. }
+            | ClientMessage::Merge { store, .. }
+            | ClientMessage::Apply { store, .. }
+            | ClientMessage::Subscribe { store, .. }
+            | ClientMessage::Unsubscribe { store, .. } => Some(store),
+        }
+    }
 }
 
 #[derive(Type, Clone, Debug, Serialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
-    Update { store: String, value: String },
+    // a plain string, or `{ content_type, data }` for blob stores
+    Update {
+        store: String,
+        value: StoreValue,
+        version: u64,
+    },
+    // sent instead of an `Update` when a json store was patched
+    Patch {
+        store: String,
+        ops: Vec<PatchOp>,
+        version: u64,
+    },
+    Merge {
+        store: String,
+        patch: Value,
+        version: u64,
+    },
+    // crdt stores send their full state instead of an `Update`, and the
+    // operations that changed it afterwards
+    State {
+        store: String,
+        state: Crdt,
+        version: u64,
+    },
+    Ops {
+        store: String,
+        ops: Vec<CrdtOp>,
+        version: u64,
+    },
+    // oldest first, only the latest revisions of a store are retained
+    History {
+        store: String,
+        revisions: Vec<Revision>,
+    },
+    Conflict {
+        store: String,
+        expected_version: u64,
+        version: u64,
+        request_id: Option<u32>,
+    },
+    // sent once a message with an `id` has been handled, `version` is the
+    // version of the store after handling it
+    Ack {
+        id: u32,
+        version: u64,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+        store: Option<String>,
+        request_id: Option<u32>,
+    },
+}
+
+#[derive(Type, Clone, Copy, Debug, Serialize)]
+pub enum ErrorCode {
+    PermissionDenied,
+    UnknownStore,
+    InvalidMessage,
+    TooLarge,
+    InvalidValue,
+    // sent without a request once a socket's token expired
+    TokenExpired,
+    // the socket, its key or its namespace sent more than its rate limits allow
+    RateLimited,
+    // the namespace can not hold any more stores
+    TooManyStores,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn describe(self) -> &'static str {
+        match self {
+            ErrorCode::PermissionDenied => "Not permitted for this store",
+            ErrorCode::UnknownStore => "Store does not exist",
+            ErrorCode::InvalidMessage => "Message could not be parsed",
+            ErrorCode::TooLarge => "Message is too large",
+            ErrorCode::InvalidValue => "Value does not fit the store",
+            ErrorCode::TokenExpired => "Token has expired",
+            ErrorCode::RateLimited => "Too many messages, slow down",
+            ErrorCode::TooManyStores => "Namespace has too many stores",
+            ErrorCode::Internal => "Message could not be handled",
+        }
+    }
 }
 
 macro_rules! specta_buffer {
     {$($types:ty)|* ,$s:expr} => {
         {
             let mut buffer = String::from("/** this file is automatically generated, do not edit **/\n\n");
-            $(buffer += &specta::ts::export::<$types>(&Default::default()).expect("Failed to export types"); buffer += ";\n";)*
+            let config = ExportConfiguration::default().bigint(BigIntExportBehavior::Number);
+            $(buffer += &specta::ts::export::<$types>(&config).expect("Failed to export types"); buffer += ";\n";)*
             buffer += $s;
             buffer
         }
@@ -37,14 +242,24 @@ pub fn export_types(path: &str) {
         std::fs::remove_file(path).expect("Failed to remove old types");
     }
 
+    // the protocols the server accepts in `Sec-WebSocket-Protocol`, in the
+    // order clients should offer them
+    let protocols: Vec<String> = Protocol::ALL
+        .iter()
+        .map(|protocol| format!("\"{}\"", protocol.name()))
+        .collect();
     let definitions = specta_buffer! {
-        ServerMessage | ClientMessage,
-        r#"
+        ServerMessage | ClientMessage | ErrorCode | StoreKind | StoreValue | PatchOp | Revision
+            | Crdt | CrdtOp | OpId | Rga | RgaElement | OrMap | MapEntry | Protocol,
+        &format!(r#"
 export type ClientMessageTypes = ClientMessage["type"];
 export type ServerMessageTypes = ServerMessage["type"];
-export type ServerMessageMap<T extends ServerMessageTypes> = Omit<Extract<ServerMessage, { type: T }>, 'type'>;
-export type ClientMessageMap<T extends ClientMessageTypes> = Omit<Extract<ClientMessage, { type: T }>, 'type'>;
-"#
+export type ServerMessageMap<T extends ServerMessageTypes> = Omit<Extract<ServerMessage, {{ type: T }}>, 'type'>;
+export type ClientMessageMap<T extends ClientMessageTypes> = Omit<Extract<ClientMessage, {{ type: T }}>, 'type'>;
+// text frames are always json, binary frames are in the protocol the socket negotiated
+export const PROTOCOLS: Protocol[] = [{}];
+export type Codec = {{ protocol: Protocol; encode: (message: ClientMessage) => string | Uint8Array; decode: (data: string | ArrayBuffer) => ServerMessage }};
+"#, protocols.join(", "))
     };
 
     let mut file = std::fs::File::create(path).expect("Failed to create types");
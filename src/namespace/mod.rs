@@ -1,185 +1,1643 @@
+pub mod access;
+pub mod limit;
 pub mod messages;
+pub mod session;
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use axum::extract::ws::WebSocket;
-use futures::{channel::mpsc::UnboundedReceiver, StreamExt};
+use futures::{
+    channel::mpsc::{Receiver, Sender},
+    FutureExt, SinkExt, StreamExt,
+};
+use hashbrown::HashMap;
 
-use moka::future::Cache;
+use moka::{future::Cache, notification::RemovalCause, policy::EvictionPolicy};
+use serde::Serialize;
+use tokio::sync::RwLock;
 
 use crate::{
-    store::{Store, StoreInner},
+    config::NamespaceOptions,
+    persist::{Compression, NamespaceRecord, Persistence, StoreRecord},
+    secret,
+    store::{
+        crdt::{Crdt, CrdtOp},
+        json::{self, JsonChange},
+        Entry, Revision, Store, StoreInner, StoreKind, Value,
+    },
     ws::{
         pool::{WebSocketPool, WebSocketPoolInner},
-        socket::SocketId,
-        TaggedMessage,
+        socket::{SocketId, Topic},
+        upgrade::WebSocket,
+        FrameError, TaggedMessage,
     },
 };
 
-use messages::{ClientMessage, ServerMessage};
+use access::{now, AccessList, Capability, Role, Source};
+use limit::{take_all, Bucket, PeerLimits, Strikes};
+use messages::{ClientMessage, ErrorCode, ServerMessage};
+use session::{Hold, Session, SessionInner};
+
+#[derive(Clone, Debug)]
+pub enum WriteError {
+    NamespaceNotFound,
+    InvalidWriteKey,
+    StoreNotFound,
+    // the store is at a different version than the writer expected
+    Conflict { version: u64 },
+    // the value or patch does not fit the kind of store
+    Invalid(String),
+    RateLimited,
+    // the value is larger than the limit, in bytes
+    TooLarge(usize),
+    TooManyStores,
+    // the change could not be saved to the backend, so it was not applied
+    Persist,
+}
+
+#[derive(Clone, Debug)]
+pub enum Change {
+    Value(Value),
+    Json(JsonChange),
+    Crdt(Vec<CrdtOp>),
+}
+
+// a change and the conditions it is applied under
+#[derive(Clone, Debug)]
+pub struct Write {
+    pub change: Change,
+    pub expected_version: Option<u64>,
+    // the kind of store to create if a value is written to a missing store
+    pub kind: StoreKind,
+    // who made the change, kept in the store's history
+    pub author: String,
+}
+
+// too many unknown keys were checked recently, see `RateLimits::auth`
+#[derive(Clone, Copy, Debug)]
+pub struct AuthLimited;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct NamespaceInfo {
+    pub name: String,
+    pub connections: u64,
+    pub stores: Vec<String>,
+    pub write_keys: usize,
+}
 
 pub type Namespace = Arc<NamespaceInner>;
 pub struct NamespaceInner {
-    write_key: String,
+    name: String,
+    // hashes of the keys that can write every store, there can be several
+    // while clients move over to a new key
+    write_keys: RwLock<Vec<String>>,
+    access: RwLock<AccessList>,
+    // whether the namespace comes from the config file
+    configured: AtomicBool,
+    // results of checking keys against the hashes, by the key's digest
+    verified: Cache<[u8; 32], Option<Capability>>,
+    backend: Option<Persistence>,
+    // how values are compressed when they are persisted, from the config
+    compression: RwLock<Compression>,
+
+    options: NamespaceOptions,
+    // shared by every socket and request of the namespace
+    namespace_limit: Arc<Bucket>,
+    // keys that have to be hashed to be checked, by the address they came from
+    auth_limits: PeerLimits,
+    // shared by everyone using the same key, by the key's hash
+    key_limits: Cache<String, Arc<Bucket>>,
 
-    pool: WebSocketPool<ClientMessage, bool>,
+    pool: WebSocketPool<ClientMessage, Session>,
     stores: Cache<String, Store<SocketId>>,
+    // how many stores exist, including persisted ones that are not in memory
+    store_count: Arc<AtomicUsize>,
+    // the subscribers of stores that were evicted, they are subscribed to the
+    // store again once it is loaded or created
+    detached: Arc<Mutex<HashMap<String, Detached>>>,
+}
+
+// a store that was evicted while sockets were subscribed to it. without a
+// backend it is created again from scratch, and continues after `version` so
+// its subscribers don't take the new value for an old one
+#[derive(Default)]
+struct Detached {
+    version: u64,
+    subscribers: Vec<SocketId>,
 }
 
 impl NamespaceInner {
-    pub async fn new(write_key: String) -> Namespace {
-        let (pool, listener) = WebSocketPoolInner::new();
+    pub async fn new(
+        name: String,
+        write_keys: Vec<String>,
+        access: AccessList,
+        backend: Option<Persistence>,
+        options: NamespaceOptions,
+    ) -> Namespace {
+        let (pool, listener) = WebSocketPoolInner::new(
+            options.max_message_size,
+            options.inbound_queue,
+            options.outbound_queue,
+            options.overflow,
+        );
+
+        // without a backend a store is gone once it is evicted. either way its
+        // subscribers are kept, for when it comes back
+        let store_count = Arc::new(AtomicUsize::new(0));
+        let evicted = store_count.clone();
+        let detached: Arc<Mutex<HashMap<String, Detached>>> = Arc::default();
+        let detaching = detached.clone();
+        let persisted = backend.is_some();
+        // the default policy may turn a new store away right after it was
+        // written if it is used less than the others. that is fine while it
+        // can be loaded again, without a backend it would be lost, so the
+        // least recently used stores make room instead
+        let policy = if persisted {
+            EvictionPolicy::default()
+        } else {
+            EvictionPolicy::lru()
+        };
+        let stores = Cache::builder()
+            .eviction_policy(policy)
+            .time_to_idle(options.store_idle)
+            .weigher(|_, store: &Store<SocketId>| store.weight())
+            .max_capacity(options.max_memory)
+            .async_eviction_listener(move |name: Arc<String>, store: Store<SocketId>, cause| {
+                if cause == RemovalCause::Replaced {
+                    return async {}.boxed();
+                }
+                if !persisted {
+                    evicted.fetch_sub(1, Ordering::Relaxed);
+                }
+
+                let detached = detaching.clone();
+                async move {
+                    let subscribers = store.subscibers().await;
+                    if subscribers.is_empty() {
+                        return;
+                    }
+                    let version = store.entry().await.version;
+                    let mut detached = detached.lock().unwrap_or_else(|e| e.into_inner());
+                    let detached = detached.entry((*name).clone()).or_default();
+                    detached.version = detached.version.max(version);
+                    detached.subscribers.extend(subscribers);
+                }
+                .boxed()
+            })
+            .build();
+
+        // keys that are not hashed yet, e.g. from older records, are hashed here
+        let (write_keys, access) = secret::blocking(move || {
+            let write_keys = write_keys.into_iter().map(secret::hashed).collect();
+            (write_keys, access.hashed(&AccessList::default()))
+        })
+        .await;
 
         let this = Arc::new(Self {
-            write_key,
+            name,
+            write_keys: RwLock::new(write_keys),
+            access: RwLock::new(access),
+            configured: AtomicBool::new(false),
+            verified: Cache::builder()
+                .max_capacity(1024)
+                .time_to_live(Duration::from_secs(600))
+                .build(),
+            backend,
+            compression: RwLock::new(options.compression),
 
-            pool,
-            stores: Cache::builder()
-                .time_to_idle(Duration::from_secs(3600 * 12))
+            options,
+            namespace_limit: Bucket::new(options.rate_limits.namespace),
+            auth_limits: PeerLimits::new(options.rate_limits.auth),
+            key_limits: Cache::builder()
+                .time_to_idle(Duration::from_secs(600))
                 .build(),
+
+            pool,
+            stores,
+            store_count,
+            detached,
         });
 
+        this.restore().await;
         this.start(listener).await;
 
         this
     }
 
-    pub async fn read_store(self: &Arc<Self>, name: &String) -> Option<String> {
-        let store = self.stores.get(name).await?;
-        Some(store.get().await)
+    pub fn name(&self) -> &String {
+        &self.name
     }
 
-    pub async fn write_store(
+    // returns whether it changed, it is saved with the next `persist`
+    pub fn set_configured(&self, configured: bool) -> bool {
+        self.configured.swap(configured, Ordering::Relaxed) != configured
+    }
+
+    // loads every persisted store back into the cache
+    async fn restore(self: &Arc<Self>) {
+        let Some(backend) = &self.backend else {
+            return;
+        };
+
+        match backend.load_stores(&self.name).await {
+            Ok(stores) => {
+                self.store_count.store(stores.len(), Ordering::Relaxed);
+                for (name, mut record) in stores {
+                    if let Err(e) = record.decompress() {
+                        error!("Error decompressing store {}/{}: {}", self.name, name, e);
+                        continue;
+                    }
+                    let store =
+                        StoreInner::new(record.value, record.version, record.kind, record.crdt);
+                    self.stores.insert(name, store).await;
+                }
+            }
+            Err(e) => error!("Error restoring stores of {}: {}", self.name, e),
+        }
+    }
+
+    pub async fn persist(self: &Arc<Self>) {
+        let Some(backend) = &self.backend else {
+            return;
+        };
+
+        let record = NamespaceRecord {
+            write_key: None,
+            write_keys: self.write_keys.read().await.clone(),
+            access: self.access.read().await.clone(),
+            configured: self.configured.load(Ordering::Relaxed),
+        };
+        if let Err(e) = backend.save_namespace(&self.name, &record).await {
+            error!("Error persisting namespace {}: {}", self.name, e);
+        }
+    }
+
+    // removes the namespace and all of its stores from the backend
+    pub async fn erase(self: &Arc<Self>) {
+        let Some(backend) = &self.backend else {
+            return;
+        };
+
+        if let Err(e) = backend.delete_namespace(&self.name).await {
+            error!("Error erasing namespace {}: {}", self.name, e);
+        }
+    }
+
+    async fn persist_store(
         self: &Arc<Self>,
-        name: &String,
-        write_key: &String,
-        value: String,
-    ) -> Result<(), &'static str> {
-        if write_key != &self.write_key {
-            return Err("Invalid write key");
+        name: &str,
+        kind: StoreKind,
+        entry: &Entry,
+        crdt: Option<&Crdt>,
+    ) -> Result<(), WriteError> {
+        let Some(backend) = &self.backend else {
+            return Ok(());
+        };
+
+        let mut record = StoreRecord {
+            value: entry.value.clone(),
+            compressed: None,
+            version: entry.version,
+            kind,
+            crdt: crdt.cloned(),
+        };
+        // values that fail to compress are saved as they are
+        let compression = *self.compression.read().await;
+        if let Err(e) = record.compress(compression) {
+            error!("Error compressing store {}/{}: {}", self.name, name, e);
+        }
+        if let Err(e) = backend.save_store(&self.name, name, &record).await {
+            error!("Error persisting store {}/{}: {}", self.name, name, e);
+            return Err(WriteError::Persist);
         }
+        Ok(())
+    }
 
-        let store = self.stores.get(name).await;
-        if let Some(store) = store {
-            store.set(value.clone()).await;
+    // looks up a store, falling back to the backend if it was evicted from the cache
+    pub async fn get_store(self: &Arc<Self>, name: &String) -> Option<Store<SocketId>> {
+        if let Some(store) = self.stores.get(name).await {
+            return Some(store);
+        }
 
-            let mut subscribers = store.subscibers().await;
-            let message = ServerMessage::Update {
-                store: name.clone(),
-                value,
+        let backend = self.backend.as_ref()?;
+        let mut record = match backend.load_store(&self.name, name).await {
+            Ok(record) => record?,
+            Err(e) => {
+                error!("Error loading store {}/{}: {}", self.name, name, e);
+                return None;
+            }
+        };
+        if let Err(e) = record.decompress() {
+            error!("Error decompressing store {}/{}: {}", self.name, name, e);
+            return None;
+        }
+
+        let store = self
+            .stores
+            .get_with(name.clone(), async {
+                StoreInner::new(record.value, record.version, record.kind, record.crdt)
+            })
+            .await;
+        self.reattach(name, &store).await;
+        Some(store)
+    }
+
+    // subscribes the sockets that were subscribed to the store before it was
+    // evicted, as long as they are still connected and may still read it
+    async fn reattach(self: &Arc<Self>, name: &str, store: &Store<SocketId>) {
+        let subscribers = self
+            .detached
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(name)
+            .map(|detached| detached.subscribers);
+        for socket_id in subscribers.into_iter().flatten() {
+            let Some(session) = self.pool.tag(socket_id) else {
+                continue;
             };
-            let _ = self.pool.send_to_many(&mut subscribers, message).await;
+            if session.can_read(name) {
+                store.subscribe(socket_id).await;
+            }
+        }
+    }
 
-            return Ok(());
+    // writes through to the backend while holding the store's lock, so the
+    // persisted value is always the one with the highest version. the change
+    // is only applied once it was saved. returns the new version and the
+    // change to send to subscribers, if anything changed
+    async fn set_store(
+        self: &Arc<Self>,
+        name: &str,
+        store: &Store<SocketId>,
+        change: Change,
+        expected_version: Option<u64>,
+        author: String,
+    ) -> Result<(u64, Option<Change>), WriteError> {
+        let mut entry = store.lock().await;
+        if let Some(expected_version) = expected_version {
+            if entry.version != expected_version {
+                return Err(WriteError::Conflict {
+                    version: entry.version,
+                });
+            }
         }
 
-        self.new_store(name.clone(), value).await;
+        // the crdt state the value was materialized from
+        let mut next_crdt = None;
+        let (value, change) = match (change, store.kind()) {
+            (Change::Value(value), kind @ (StoreKind::Text | StoreKind::Blob)) => {
+                let value = value.into_kind(kind).map_err(WriteError::Invalid)?;
+                (value.clone(), Change::Value(value))
+            }
+            (Change::Value(value), StoreKind::Json) => {
+                let value = value
+                    .into_kind(StoreKind::Json)
+                    .map_err(WriteError::Invalid)?;
+                json::validate(value.text()).map_err(WriteError::Invalid)?;
+                (value.clone(), Change::Value(value))
+            }
+            (Change::Json(change), StoreKind::Json) => {
+                let value =
+                    json::apply(entry.value.text(), &change).map_err(WriteError::Invalid)?;
+                (Value::Text(value), Change::Json(change))
+            }
+            (Change::Crdt(ops), kind) if kind.is_crdt() => {
+                // the operations are applied to a copy, which only replaces
+                // the state once the write went through
+                let Some(mut crdt) = store.crdt().map(|crdt| crdt.clone()) else {
+                    return Err(WriteError::Invalid("Store has no crdt state".into()));
+                };
+
+                let held = crdt.pending();
+                let applied = crdt.apply(ops).map_err(WriteError::Invalid)?;
+
+                // the whole state counts against the limit, including
+                // tombstones and held back operations. a store over the limit
+                // can still take operations that don't make it any larger
+                let size = crdt.size();
+                if size > self.options.max_value_size && size > store.crdt_size() {
+                    return Err(WriteError::TooLarge(self.options.max_value_size));
+                }
+
+                // operations that were only held back leave the value and
+                // its version as they are, but are kept with the state
+                if applied.is_empty() {
+                    if crdt.pending() > held {
+                        if entry.value.size() + size > self.max_weight() {
+                            return Err(WriteError::TooLarge(self.max_weight()));
+                        }
+                        self.persist_store(name, store.kind(), &entry, Some(&crdt))
+                            .await?;
+                        store.replace_crdt(crdt, size);
+                        self.reweigh(name, store).await;
+                    }
+                    return Ok((entry.version, None));
+                }
+
+                let value = Value::Text(crdt.materialize());
+                next_crdt = Some((crdt, size));
+                (value, Change::Crdt(applied))
+            }
+            (Change::Json(_), StoreKind::Text | StoreKind::Blob) => {
+                return Err(WriteError::Invalid("Store does not hold json".into()))
+            }
+            (Change::Crdt(_), _) => return Err(WriteError::Invalid("Store is not a crdt".into())),
+            (_, _) => {
+                return Err(WriteError::Invalid(
+                    "Crdt stores only accept crdt operations".into(),
+                ))
+            }
+        };
+
+        if !store.kind().is_crdt() && value.size() > self.options.max_value_size {
+            return Err(WriteError::TooLarge(self.options.max_value_size));
+        }
+        // the history makes way for the value, but a store never weighs more
+        // than the namespace may hold, or it would be evicted right away
+        let crdt_size = next_crdt.as_ref().map_or(0, |(_, size)| *size);
+        if value.size() + crdt_size > self.max_weight() {
+            return Err(WriteError::TooLarge(self.max_weight()));
+        }
 
+        let next = Entry {
+            value,
+            version: entry.version + 1,
+        };
+        let crdt = next_crdt.as_ref().map(|(crdt, _)| crdt);
+        self.persist_store(name, store.kind(), &next, crdt).await?;
+        if let Some((crdt, size)) = next_crdt {
+            store.replace_crdt(crdt, size);
+        }
+        *entry = next;
+        store.record(&entry, Some(author), self.max_weight());
+        self.reweigh(name, store).await;
+        Ok((entry.version, Some(change)))
+    }
+
+    // the most a single store may weigh
+    fn max_weight(&self) -> usize {
+        self.options.max_memory.try_into().unwrap_or(usize::MAX)
+    }
+
+    // the cache only weighs stores when they are inserted
+    async fn reweigh(self: &Arc<Self>, name: &str, store: &Store<SocketId>) {
+        if let Some(cached) = self.stores.get(name).await {
+            if Arc::ptr_eq(&cached, store) {
+                self.stores.insert(name.to_owned(), cached).await;
+            }
+        }
+    }
+
+    // applies a change to a store, creating the store as `kind` if a plain
+    // value is written to a store that does not exist yet, and sends the
+    // change to its subscribers. an `expected_version` of 0 only matches a
+    // store that does not exist yet
+    pub async fn write(self: &Arc<Self>, name: &String, write: Write) -> Result<u64, WriteError> {
+        let Write {
+            change,
+            expected_version,
+            kind,
+            author,
+        } = write;
+
+        let store = match self.get_store(name).await {
+            Some(store) => store,
+            None => {
+                if matches!(expected_version, Some(v) if v != 0) {
+                    return Err(WriteError::Conflict { version: 0 });
+                }
+
+                let Change::Value(value) = &change else {
+                    return Err(WriteError::StoreNotFound);
+                };
+
+                let (store, created) = self
+                    .create_store(name.clone(), value.clone(), kind, author.clone())
+                    .await?;
+                if created {
+                    return Ok(store.entry().await.version);
+                }
+                store
+            }
+        };
+
+        let (version, change) = self
+            .set_store(name, &store, change, expected_version, author)
+            .await?;
+        let Some(change) = change else {
+            return Ok(version);
+        };
+
+        let store_name = name.clone();
+        let message = match change {
+            Change::Value(value) => ServerMessage::Update {
+                store: store_name,
+                value,
+                version,
+            },
+            Change::Json(JsonChange::Patch(ops)) => ServerMessage::Patch {
+                store: store_name,
+                ops,
+                version,
+            },
+            Change::Json(JsonChange::Merge(patch)) => ServerMessage::Merge {
+                store: store_name,
+                patch,
+                version,
+            },
+            Change::Crdt(ops) => ServerMessage::Ops {
+                store: store_name,
+                ops,
+                version,
+            },
+        };
+
+        // a new value makes any change still queued for the store redundant
+        let topic = Topic {
+            key: name,
+            replaces: matches!(message, ServerMessage::Update { .. }),
+        };
+        let mut subscribers = store.subscibers().await;
+        let _ = self
+            .pool
+            .send_to_many(&mut subscribers, message, Some(topic))
+            .await;
+
+        // if the message failed to send, remove the socket from the store
+        store.unsubscribe_many(&subscribers).await;
+
+        Ok(version)
+    }
+
+    pub async fn info(self: &Arc<Self>) -> NamespaceInfo {
+        let mut stores: Vec<String> = self
+            .stores
+            .iter()
+            .map(|(name, _)| (*name).clone())
+            .collect();
+        stores.sort();
+
+        NamespaceInfo {
+            name: self.name.clone(),
+            connections: self.pool.connections(),
+            stores,
+            write_keys: self.write_keys.read().await.len(),
+        }
+    }
+
+    // replaces every write key, sockets that used a removed key are disconnected
+    pub async fn set_write_keys(self: &Arc<Self>, write_keys: Vec<String>) {
+        let previous = self.write_keys.read().await.clone();
+        let hashes = secret::blocking(move || {
+            write_keys
+                .into_iter()
+                .map(|key| secret::rehashed(key, &previous))
+                .collect()
+        })
+        .await;
+        *self.write_keys.write().await = hashes;
+        self.verified.invalidate_all();
+        self.persist().await;
+        self.revoke(true).await;
+    }
+
+    pub async fn add_write_key(self: &Arc<Self>, write_key: String) {
+        let hash = secret::blocking(move || secret::hash(&write_key)).await;
+        self.write_keys.write().await.push(hash);
+        self.verified.invalidate_all();
+        self.persist().await;
+    }
+
+    pub async fn remove_write_key(self: &Arc<Self>, write_key: String) -> Result<(), &'static str> {
+        let hashes = self.write_keys.read().await.clone();
+        let remaining: Vec<String> = secret::blocking(move || {
+            hashes
+                .into_iter()
+                .filter(|hash| !secret::verify(&write_key, hash))
+                .collect()
+        })
+        .await;
+
+        let mut write_keys = self.write_keys.write().await;
+        if remaining.len() == write_keys.len() {
+            return Err("Write key not found");
+        }
+        *write_keys = remaining;
+        drop(write_keys);
+
+        self.verified.invalidate_all();
+        self.persist().await;
+        self.revoke(true).await;
         Ok(())
     }
 
-    pub async fn new_store(self: &Arc<Self>, name: String, value: String) -> Store<SocketId> {
-        let store = StoreInner::new(value);
-        self.stores.insert(name, store.clone()).await;
-        store
+    // stores are compressed with the new setting the next time they are written
+    pub async fn set_compression(self: &Arc<Self>, compression: Compression) {
+        *self.compression.write().await = compression;
+    }
+
+    pub async fn access(self: &Arc<Self>) -> AccessList {
+        self.access.read().await.clone()
+    }
+
+    // like the write keys, sockets that used a removed key or a token signed
+    // with a replaced secret are disconnected
+    pub async fn set_access(self: &Arc<Self>, access: AccessList) {
+        let previous = self.access.read().await.clone();
+        let tokens_valid = previous.token_secret == access.token_secret;
+
+        let access = secret::blocking(move || access.hashed(&previous)).await;
+        *self.access.write().await = access;
+        self.verified.invalidate_all();
+        self.persist().await;
+        self.revoke(tokens_valid).await;
+    }
+
+    // takes away what removed keys granted, and the rules keys lost. sockets
+    // that authenticated with one of them are disconnected, the others are
+    // only dropped from the stores that are no longer public
+    async fn revoke(self: &Arc<Self>, tokens_valid: bool) {
+        let write_keys = self.write_keys.read().await.clone();
+        let access = self.access.read().await.clone();
+        let public = access.public();
+
+        for (socket_id, session) in self.pool.tagged() {
+            if !session
+                .origin()
+                .is_none_or(|origin| access.allows_origin(origin))
+            {
+                info!(
+                    "Disconnecting socket {} from {}, its origin is no longer allowed",
+                    socket_id, self.name
+                );
+                self.pool.disconnect(socket_id).await;
+                continue;
+            }
+
+            // a key that is still there may have lost some of its rules
+            let revoked = session.revoke(|source, rule| match source {
+                Source::Public => true,
+                Source::WriteKey(hash) => write_keys.contains(hash),
+                Source::Key(hash) => access
+                    .keys
+                    .iter()
+                    .any(|key| key.key == *hash && key.rules.contains(rule)),
+                Source::Token => tokens_valid,
+            });
+            if revoked {
+                info!(
+                    "Disconnecting socket {} from {}, its key was removed or narrowed",
+                    socket_id, self.name
+                );
+                self.pool.disconnect(socket_id).await;
+                continue;
+            }
+
+            session.replace_public(public.clone());
+            self.unsubscribe_unreadable(socket_id, &session).await;
+        }
+    }
+
+    async fn unsubscribe_unreadable(self: &Arc<Self>, socket_id: SocketId, session: &Session) {
+        for (name, store) in self.stores.iter() {
+            if !session.can_read(&name) {
+                store.unsubscribe(&socket_id).await;
+            }
+        }
+        self.forget_detached(socket_id, |name| session.can_read(name));
+    }
+
+    // drops the socket from the subscribers of evicted stores it may no
+    // longer read
+    fn forget_detached(&self, socket_id: SocketId, readable: impl Fn(&str) -> bool) {
+        let mut detached = self.detached.lock().unwrap_or_else(|e| e.into_inner());
+        detached.retain(|name, detached| {
+            if !readable(name) {
+                detached.subscribers.retain(|id| *id != socket_id);
+            }
+            !detached.subscribers.is_empty()
+        });
+    }
+
+    // disconnects every socket and stops the message loop
+    pub async fn shutdown(self: &Arc<Self>) {
+        self.pool.close().await;
+    }
+
+    pub async fn read_store(self: &Arc<Self>, name: &String) -> Option<(Entry, StoreKind)> {
+        let store = self.get_store(name).await?;
+        Some((store.entry().await, store.kind()))
+    }
+
+    // the retained revisions of a store newer than `since_version`
+    pub async fn history(
+        self: &Arc<Self>,
+        name: &String,
+        since_version: u64,
+    ) -> Option<Vec<Revision>> {
+        let store = self.get_store(name).await?;
+        Some(store.history(since_version))
+    }
+
+    // the value a store had at `version`. the outer `None` means the store
+    // does not exist, the inner one that the version is no longer retained
+    pub async fn read_version(
+        self: &Arc<Self>,
+        name: &String,
+        version: u64,
+    ) -> Option<Option<(Entry, StoreKind)>> {
+        let store = self.get_store(name).await?;
+
+        let entry = store.entry().await;
+        if entry.version == version {
+            return Some(Some((entry, store.kind())));
+        }
+
+        Some(store.revision(version).map(|revision| {
+            let entry = Entry {
+                value: revision.value,
+                version: revision.version,
+            };
+            (entry, store.kind())
+        }))
+    }
+
+    pub async fn write_store(
+        self: &Arc<Self>,
+        name: &String,
+        write_key: &str,
+        write: Write,
+        size: usize,
+        peer: IpAddr,
+    ) -> Result<u64, WriteError> {
+        let capability = match self.authenticate(write_key, peer).await {
+            Ok(Some(capability)) if capability.can_write(name) => capability,
+            Err(AuthLimited) => return Err(WriteError::RateLimited),
+            _ => return Err(WriteError::InvalidWriteKey),
+        };
+
+        // requests count against the limits of their key and the namespace
+        let key_limits = self.key_limits(&capability).await;
+        let mut limits: Vec<&Bucket> = key_limits.iter().map(|limit| &**limit).collect();
+        limits.push(&self.namespace_limit);
+        if take_all(&limits, size).is_err() {
+            return Err(WriteError::RateLimited);
+        }
+
+        self.write(name, write).await
+    }
+
+    // returns the store and whether it was created by this call
+    pub async fn create_store(
+        self: &Arc<Self>,
+        name: String,
+        value: Value,
+        kind: StoreKind,
+        author: String,
+    ) -> Result<(Store<SocketId>, bool), WriteError> {
+        if name.len() > self.options.max_store_name {
+            return Err(WriteError::Invalid(format!(
+                "Store names can be at most {} bytes",
+                self.options.max_store_name
+            )));
+        }
+        if value.size() > self.options.max_value_size {
+            return Err(WriteError::TooLarge(self.options.max_value_size));
+        }
+        let value = value.into_kind(kind).map_err(WriteError::Invalid)?;
+        if kind == StoreKind::Json {
+            json::validate(value.text()).map_err(WriteError::Invalid)?;
+        }
+        // crdt stores are limited by the size of their state, which is
+        // larger than the value it is seeded from
+        let crdt = Crdt::seed(kind, value.text());
+        let crdt_size = crdt.as_ref().map_or(0, Crdt::size);
+        if crdt_size > self.options.max_value_size {
+            return Err(WriteError::TooLarge(self.options.max_value_size));
+        }
+        if value.size() + crdt_size > self.max_weight() {
+            return Err(WriteError::TooLarge(self.max_weight()));
+        }
+
+        // a store created again after it was evicted continues its versions
+        let version = self
+            .detached
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&name)
+            .map_or(1, |detached| detached.version + 1);
+
+        // the store is counted before it is inserted, by whoever inserts it,
+        // so stores created at the same time can't go past `max_stores`
+        let max_stores = self.options.max_stores;
+        let entry = self
+            .stores
+            .entry(name.clone())
+            .or_try_insert_with(async {
+                self.store_count
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                        (count < max_stores).then_some(count + 1)
+                    })
+                    .map(|_| StoreInner::new(value, version, kind, crdt))
+            })
+            .await
+            .map_err(|_| WriteError::TooManyStores)?;
+
+        let created = entry.is_fresh();
+        let store = entry.into_value();
+        if created {
+            let entry = store.lock().await;
+            // a store that could not be saved is not kept around either. only
+            // a backend can fail to save, and the eviction listener leaves the
+            // count of persisted stores alone
+            let crdt = store.crdt().map(|crdt| crdt.clone());
+            let persisted = self.persist_store(&name, kind, &entry, crdt.as_ref()).await;
+            if let Err(e) = persisted {
+                self.store_count.fetch_sub(1, Ordering::Relaxed);
+                self.stores.invalidate(&name).await;
+                return Err(e);
+            }
+            store.record(&entry, Some(author), self.max_weight());
+            drop(entry);
+            self.reattach(&name, &store).await;
+            self.reweigh(&name, &store).await;
+
+            // the sockets that were subscribed before it was evicted
+            let mut subscribers = store.subscibers().await;
+            if !subscribers.is_empty() {
+                let (message, _) = current(name.clone(), &store).await;
+                let topic = Topic {
+                    key: &name,
+                    replaces: true,
+                };
+                let _ = self
+                    .pool
+                    .send_to_many(&mut subscribers, message, Some(topic))
+                    .await;
+            }
+        }
+
+        Ok((store, created))
     }
 
+    // messages are handed to a fixed set of workers by the store they are
+    // about, so different stores are handled in parallel while the messages
+    // of a store keep their order. `Auth` changes what a socket's following
+    // messages may do, but checking a key takes a while, so it is handled on
+    // a task of its own while the socket's following messages are held back
     async fn start(
         self: &Arc<Self>,
-        mut listener: UnboundedReceiver<TaggedMessage<ClientMessage, bool>>,
+        mut listener: Receiver<TaggedMessage<ClientMessage, Session>>,
     ) {
+        let workers = self.options.workers;
+        let capacity = (self.options.inbound_queue / workers).max(1);
+        let mut inboxes = Vec::with_capacity(workers);
+        for _ in 0..workers {
+            let (tx, rx) = futures::channel::mpsc::channel(capacity);
+            tokio::task::spawn(self.clone().work(rx));
+            inboxes.push(tx);
+        }
+
         let this = self.clone();
         tokio::task::spawn(async move {
             while let Some(TaggedMessage {
                 mut socket_id,
                 message,
-                tag: can_write,
+                tag: session,
             }) = listener.next().await
             {
-                match message {
-                    ClientMessage::Subscribe {
-                        store: store_name,
-                        initial,
-                    } => {
-                        let store = match this.stores.get(&store_name).await {
-                            Some(store) => store,
-                            None => {
-                                if !can_write {
-                                    continue;
-                                }
-                                this.new_store(store_name.clone(), initial.clone()).await
-                            }
+                let message = match message {
+                    Ok(message) => message,
+                    Err(e) => {
+                        let (code, message) = match e {
+                            FrameError::Malformed(e) => (ErrorCode::InvalidMessage, e),
+                            FrameError::RateLimited => (
+                                ErrorCode::RateLimited,
+                                ErrorCode::RateLimited.describe().to_owned(),
+                            ),
+                            FrameError::TooLarge(size) => (
+                                ErrorCode::TooLarge,
+                                format!(
+                                    "Message of {} bytes exceeds {}",
+                                    size,
+                                    this.pool.max_message_size()
+                                ),
+                            ),
                         };
 
-                        store.subscribe(socket_id).await;
-
-                        let value = store.get().await;
-                        let message = ServerMessage::Update {
-                            store: store_name,
-                            value,
+                        let message = ServerMessage::Error {
+                            code,
+                            message,
+                            store: None,
+                            request_id: None,
                         };
                         let _ = this.pool.send_to(&mut socket_id, message).await;
+                        continue;
                     }
-                    ClientMessage::Unsubscribe { store } => {
-                        if let Some(store) = this.stores.get(&store).await {
-                            store.unsubscribe(&socket_id).await;
-                        }
-                    }
-                    ClientMessage::Set {
-                        store: store_name,
-                        value,
-                    } => {
-                        if !can_write {
-                            continue;
-                        }
+                };
 
-                        let store = match this.stores.get(&store_name).await {
-                            Some(store) => store,
-                            None => {
-                                this.new_store(store_name.clone(), value.clone()).await;
-                                continue;
-                            }
+                // a socket sending faster than its `Auth` is checked is
+                // rejected once it has as many messages held back as fit
+                // into the queue
+                let message = match session.hold(message, this.options.inbound_queue) {
+                    Hold::Held => continue,
+                    Hold::Full(message) => {
+                        let error = ServerMessage::Error {
+                            code: ErrorCode::RateLimited,
+                            message: ErrorCode::RateLimited.describe().to_owned(),
+                            store: message.store().map(str::to_owned),
+                            request_id: message.id(),
                         };
+                        let _ = this.pool.send_to(&mut socket_id, error).await;
+                        continue;
+                    }
+                    Hold::Pass(message) => message,
+                };
 
-                        store.set(value.clone()).await;
+                let Some(store) = message.store() else {
+                    session.start_holding();
+                    let inboxes = inboxes.clone();
+                    tokio::task::spawn(
+                        this.clone().authorize(socket_id, message, session, inboxes),
+                    );
+                    continue;
+                };
 
-                        let mut subscribers = store.subscibers().await;
-                        let message = ServerMessage::Update {
-                            store: store_name,
-                            value,
-                        };
-                        let _ = this.pool.send_to_many(&mut subscribers, message).await;
+                let inbox = &mut inboxes[shard(store, workers)];
+                if inbox.send((socket_id, message, session)).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
 
-                        // if the message failed to send, remove the socket from the store
-                        store.unsubscribe_many(&subscribers).await;
-                    }
+    // handles an `Auth` message, then hands on the messages the socket sent
+    // while it was checked
+    async fn authorize(
+        self: Arc<Self>,
+        socket_id: SocketId,
+        message: ClientMessage,
+        session: Session,
+        mut inboxes: Vec<Sender<(SocketId, ClientMessage, Session)>>,
+    ) {
+        self.process(socket_id, message, &session).await;
+
+        let workers = inboxes.len();
+        while let Some(held) = session.release() {
+            for message in held {
+                let Some(store) = message.store() else {
+                    self.process(socket_id, message, &session).await;
+                    continue;
+                };
+
+                let inbox = &mut inboxes[shard(store, workers)];
+                if inbox
+                    .send((socket_id, message, session.clone()))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+
+    // handles the messages of the stores assigned to this worker in order,
+    // until the namespace shuts down
+    async fn work(self: Arc<Self>, mut inbox: Receiver<(SocketId, ClientMessage, Session)>) {
+        while let Some((socket_id, message, session)) = inbox.next().await {
+            self.process(socket_id, message, &session).await;
+        }
+    }
+
+    async fn process(
+        self: &Arc<Self>,
+        mut socket_id: SocketId,
+        message: ClientMessage,
+        session: &Session,
+    ) {
+        let id = message.id();
+        let reply = match self.handle(socket_id, message, session).await {
+            Ok(version) => match id {
+                Some(id) => ServerMessage::Ack { id, version },
+                None => return,
+            },
+            Err(reply) => reply,
+        };
+        let _ = self.pool.send_to(&mut socket_id, reply).await;
+    }
 
-                    ClientMessage::Get { store: store_name } => {
-                        if let Some(store) = this.stores.get(&store_name).await {
-                            let value = store.get().await;
+    // handles a single message from a socket, returning the version of the
+    // store it touched or the reply explaining why it was rejected
+    async fn handle(
+        self: &Arc<Self>,
+        mut socket_id: SocketId,
+        message: ClientMessage,
+        session: &Session,
+    ) -> Result<u64, ServerMessage> {
+        match message {
+            // upgrades a read only socket to write access
+            ClientMessage::Auth { token, id } => {
+                let capability = match self.authenticate(&token, session.peer()).await {
+                    Ok(Some(capability)) => capability,
+                    Ok(None) => {
+                        return Err(ServerMessage::Error {
+                            code: ErrorCode::PermissionDenied,
+                            message: "Invalid token".to_owned(),
+                            store: None,
+                            request_id: id,
+                        })
+                    }
+                    Err(AuthLimited) => {
+                        return Err(ServerMessage::Error {
+                            code: ErrorCode::RateLimited,
+                            message: ErrorCode::RateLimited.describe().to_owned(),
+                            store: None,
+                            request_id: id,
+                        })
+                    }
+                };
 
-                            let message = ServerMessage::Update {
-                                store: store_name,
-                                value,
-                            };
+                session.limit_keys(self.key_limits(&capability).await);
+                session.grant(capability);
+                if let Some(expires) = session.expires() {
+                    self.expire_at(socket_id, session.clone(), expires);
+                }
+                Ok(0)
+            }
+            ClientMessage::Subscribe {
+                store: store_name,
+                initial,
+                kind,
+                last_seen_version,
+                coalesce,
+                id,
+            } => {
+                if !session.can_read(&store_name) {
+                    return Err(error(ErrorCode::PermissionDenied, store_name, id));
+                }
 
-                            let _ = this.pool.send_to(&mut socket_id, message).await;
+                let store = match self.get_store(&store_name).await {
+                    Some(store) => store,
+                    None => {
+                        if !session.can_write(&store_name) {
+                            return Err(error(ErrorCode::UnknownStore, store_name, id));
+                        }
 
-                            // if the message failed to send, remove the socket from the store
-                            store.unsubscribe(&socket_id).await;
+                        let kind = kind.unwrap_or_default();
+                        let author = format!("socket:{}", socket_id);
+                        match self
+                            .create_store(store_name.clone(), initial.into(), kind, author)
+                            .await
+                        {
+                            Ok((store, _)) => store,
+                            Err(e) => return Err(rejected(e, store_name, None, id)),
                         }
                     }
+                };
+
+                store.subscribe(socket_id).await;
+                self.pool
+                    .coalesce(socket_id, &store_name, coalesce.unwrap_or_default())
+                    .await;
+
+                // crdt replicas are always resent in full
+                let missed = match last_seen_version {
+                    Some(seen) if !store.kind().is_crdt() => {
+                        store.missed(seen).await.map(|missed| (seen, missed))
+                    }
+                    _ => None,
+                };
+
+                let Some((seen, missed)) = missed else {
+                    let (message, version) = current(store_name, &store).await;
+                    let _ = self.pool.send_to(&mut socket_id, message).await;
+                    return Ok(version);
+                };
+
+                let version = missed.last().map_or(seen, |revision| revision.version);
+                let updates = missed.into_iter().map(|revision| ServerMessage::Update {
+                    store: store_name.clone(),
+                    value: revision.value,
+                    version: revision.version,
+                });
+                for message in updates {
+                    // `send_to` clears the id once sent
+                    let mut id = socket_id;
+                    let _ = self.pool.send_to(&mut id, message).await;
+                }
+
+                Ok(version)
+            }
+            ClientMessage::Unsubscribe {
+                store: store_name, ..
+            } => match self.get_store(&store_name).await {
+                Some(store) => {
+                    store.unsubscribe(&socket_id).await;
+                    self.pool.coalesce(socket_id, &store_name, false).await;
+                    Ok(store.entry().await.version)
+                }
+                None => Ok(0),
+            },
+            ClientMessage::Set {
+                store: store_name,
+                value,
+                id,
+            } => {
+                if !session.can_write(&store_name) {
+                    return Err(error(ErrorCode::PermissionDenied, store_name, id));
+                }
+
+                let write = Write {
+                    change: Change::Value(value.into()),
+                    expected_version: None,
+                    kind: StoreKind::Text,
+                    author: format!("socket:{}", socket_id),
+                };
+                self.write(&store_name, write)
+                    .await
+                    .map_err(|e| rejected(e, store_name, None, id))
+            }
+            ClientMessage::CompareAndSet {
+                store: store_name,
+                expected_version,
+                value,
+                id,
+            } => {
+                if !session.can_write(&store_name) {
+                    return Err(error(ErrorCode::PermissionDenied, store_name, id));
+                }
+
+                let write = Write {
+                    change: Change::Value(value.into()),
+                    expected_version: Some(expected_version),
+                    kind: StoreKind::Text,
+                    author: format!("socket:{}", socket_id),
+                };
+                self.write(&store_name, write)
+                    .await
+                    .map_err(|e| rejected(e, store_name, Some(expected_version), id))
+            }
+            ClientMessage::SetBlob {
+                store: store_name,
+                content_type,
+                data,
+                expected_version,
+                id,
+            } => {
+                if !session.can_write(&store_name) {
+                    return Err(error(ErrorCode::PermissionDenied, store_name, id));
+                }
+
+                let write = Write {
+                    change: Change::Value(Value::Blob {
+                        content_type: content_type.unwrap_or_default(),
+                        data,
+                    }),
+                    expected_version,
+                    kind: StoreKind::Blob,
+                    author: format!("socket:{}", socket_id),
+                };
+                self.write(&store_name, write)
+                    .await
+                    .map_err(|e| rejected(e, store_name, expected_version, id))
+            }
+            ClientMessage::Patch {
+                store: store_name,
+                ops,
+                expected_version,
+                id,
+            } => {
+                if !session.can_write(&store_name) {
+                    return Err(error(ErrorCode::PermissionDenied, store_name, id));
+                }
+
+                let write = Write {
+                    change: Change::Json(JsonChange::Patch(ops)),
+                    expected_version,
+                    kind: StoreKind::Json,
+                    author: format!("socket:{}", socket_id),
+                };
+                self.write(&store_name, write)
+                    .await
+                    .map_err(|e| rejected(e, store_name, expected_version, id))
+            }
+            ClientMessage::Merge {
+                store: store_name,
+                patch,
+                expected_version,
+                id,
+            } => {
+                if !session.can_write(&store_name) {
+                    return Err(error(ErrorCode::PermissionDenied, store_name, id));
+                }
+
+                let write = Write {
+                    change: Change::Json(JsonChange::Merge(patch)),
+                    expected_version,
+                    kind: StoreKind::Json,
+                    author: format!("socket:{}", socket_id),
+                };
+                self.write(&store_name, write)
+                    .await
+                    .map_err(|e| rejected(e, store_name, expected_version, id))
+            }
+            ClientMessage::Apply {
+                store: store_name,
+                ops,
+                id,
+            } => {
+                if !session.can_write(&store_name) {
+                    return Err(error(ErrorCode::PermissionDenied, store_name, id));
+                }
+
+                // crdt operations commute, so there is no expected version
+                let write = Write {
+                    change: Change::Crdt(ops),
+                    expected_version: None,
+                    kind: StoreKind::Text,
+                    author: format!("socket:{}", socket_id),
+                };
+                self.write(&store_name, write)
+                    .await
+                    .map_err(|e| rejected(e, store_name, None, id))
+            }
+
+            ClientMessage::Get {
+                store: store_name,
+                id,
+            } => {
+                if !session.can_read(&store_name) {
+                    return Err(error(ErrorCode::PermissionDenied, store_name, id));
+                }
+
+                let Some(store) = self.get_store(&store_name).await else {
+                    return Err(error(ErrorCode::UnknownStore, store_name, id));
+                };
+
+                let (message, version) = current(store_name, &store).await;
+                let _ = self.pool.send_to(&mut socket_id, message).await;
+
+                // if the message failed to send, remove the socket from the store
+                store.unsubscribe(&socket_id).await;
+
+                Ok(version)
+            }
+            ClientMessage::History {
+                store: store_name,
+                since_version,
+                id,
+            } => {
+                if !session.can_read(&store_name) {
+                    return Err(error(ErrorCode::PermissionDenied, store_name, id));
+                }
+
+                let Some(store) = self.get_store(&store_name).await else {
+                    return Err(error(ErrorCode::UnknownStore, store_name, id));
+                };
+
+                let revisions = store.history(since_version.unwrap_or_default());
+                let version = store.entry().await.version;
+                let message = ServerMessage::History {
+                    store: store_name,
+                    revisions,
+                };
+                let _ = self.pool.send_to(&mut socket_id, message).await;
+
+                Ok(version)
+            }
+        }
+    }
+
+    // what `key` grants beyond the public stores, `None` if it is neither a key
+    // of this namespace nor a valid token for it. the write keys can write every
+    // store. keys that were not checked recently count against the `auth` limit
+    // of the address they came from before they are hashed
+    pub async fn authenticate(
+        self: &Arc<Self>,
+        key: &str,
+        peer: IpAddr,
+    ) -> Result<Option<Capability>, AuthLimited> {
+        let access = self.access.read().await.clone();
+        if let Some(capability) = access.verify(&self.name, key) {
+            return Ok(Some(capability));
+        }
+
+        let digest = secret::digest(key);
+        if let Some(capability) = self.verified.get(&digest).await {
+            return Ok(capability);
+        }
+        if !self.auth_limits.take(peer, 0).await {
+            return Err(AuthLimited);
+        }
+
+        let write_keys = self.write_keys.read().await.clone();
+        let key = key.to_owned();
+        let capability = secret::blocking(move || {
+            if let Some(hash) = write_keys.iter().find(|hash| secret::verify(&key, hash)) {
+                let source = Source::WriteKey(hash.clone());
+                return Some(Capability::everything(Role::Write, source));
+            }
+            access.grants(&key)
+        })
+        .await;
+
+        self.verified.insert(digest, capability.clone()).await;
+        Ok(capability)
+    }
+
+    // once a token runs out the socket falls back to the rest of its access,
+    // and is dropped from the stores it can no longer read. a socket has a
+    // single task waiting for its grants to expire, which replaces the
+    // previous one and keeps going until no grant expires anymore
+    fn expire_at(self: &Arc<Self>, socket_id: SocketId, session: Session, expires: u64) {
+        let this = self.clone();
+        let expiring = session.clone();
+        let task = tokio::task::spawn(async move {
+            let session = expiring;
+            let mut expires = expires;
+            loop {
+                let delay = expires.saturating_sub(now());
+                tokio::time::sleep(Duration::from_secs(delay)).await;
+
+                session.forget_expired();
+                this.unsubscribe_unreadable(socket_id, &session).await;
+
+                let message = ServerMessage::Error {
+                    code: ErrorCode::TokenExpired,
+                    message: ErrorCode::TokenExpired.describe().to_owned(),
+                    store: None,
+                    request_id: None,
+                };
+                // `send_to` clears the id once sent, the socket is gone otherwise
+                let mut id = socket_id;
+                let _ = this.pool.send_to(&mut id, message).await;
+                if id != 0 {
+                    return;
+                }
+
+                match session.expires() {
+                    Some(next) => expires = next,
+                    None => return,
                 }
             }
         });
+        session.replace_expiry(task.abort_handle());
+    }
+
+    // the rate limits of the keys a capability was granted by
+    async fn key_limits(self: &Arc<Self>, capability: &Capability) -> Vec<Arc<Bucket>> {
+        let mut limits = Vec::new();
+        for key in capability.keys() {
+            let limit = self
+                .key_limits
+                .get_with(key, async { Bucket::new(self.options.rate_limits.key) })
+                .await;
+            limits.push(limit);
+        }
+        limits
+    }
+
+    // what a request with an optional key may do
+    pub async fn capability(
+        self: &Arc<Self>,
+        key: Option<&str>,
+        peer: IpAddr,
+    ) -> Result<Capability, AuthLimited> {
+        let mut capability = self.access.read().await.public();
+        if let Some(key) = key {
+            if let Some(granted) = self.authenticate(key, peer).await? {
+                capability.extend(granted);
+            }
+        }
+        Ok(capability)
+    }
+
+    // requests from browsers are only accepted from the allowed origins
+    pub async fn allows_origin(self: &Arc<Self>, origin: Option<&str>) -> bool {
+        let access = self.access.read().await;
+        origin.is_none_or(|origin| access.allows_origin(origin))
     }
 
     pub async fn add_connection(
         self: &Arc<Self>,
         websocket: WebSocket,
-        write_key: Option<&String>,
+        key: Option<&str>,
+        origin: Option<String>,
+        peer: IpAddr,
     ) {
-        let can_write = write_key == Some(&self.write_key);
-        self.pool.listen_to(websocket, can_write).await;
+        // a socket whose key could not be checked connects read only
+        let (capability, limited) = match self.capability(key, peer).await {
+            Ok(capability) => (capability, false),
+            Err(AuthLimited) => (self.access.read().await.public(), true),
+        };
+        let expires = capability.expires();
+
+        let key_limits = self.key_limits(&capability).await;
+        let session = SessionInner::new(
+            capability,
+            origin,
+            peer,
+            Bucket::new(self.options.rate_limits.socket),
+            self.namespace_limit.clone(),
+            Strikes::new(self.options.rate_limits.strikes),
+        );
+        session.limit_keys(key_limits);
+        let socket_id = self.pool.listen_to(websocket, session.clone()).await;
+
+        // a socket that is gone is not subscribed to evicted stores again
+        let this = self.clone();
+        tokio::task::spawn(async move {
+            this.pool.closed(socket_id).await;
+            this.forget_detached(socket_id, |_| false);
+        });
+
+        if limited {
+            let message = ServerMessage::Error {
+                code: ErrorCode::RateLimited,
+                message: ErrorCode::RateLimited.describe().to_owned(),
+                store: None,
+                request_id: None,
+            };
+            let mut id = socket_id;
+            let _ = self.pool.send_to(&mut id, message).await;
+        }
+        if let Some(expires) = expires {
+            self.expire_at(socket_id, session, expires);
+        }
+    }
+}
+
+// the message describing a store's current value, the full state for crdt stores
+async fn current(store_name: String, store: &Store<SocketId>) -> (ServerMessage, u64) {
+    let (Entry { value, version }, crdt) = store.snapshot().await;
+    let message = match crdt {
+        Some(state) => ServerMessage::State {
+            store: store_name,
+            state,
+            version,
+        },
+        None => ServerMessage::Update {
+            store: store_name,
+            value,
+            version,
+        },
+    };
+    (message, version)
+}
+
+fn error(code: ErrorCode, store: String, request_id: Option<u32>) -> ServerMessage {
+    ServerMessage::Error {
+        code,
+        message: code.describe().to_owned(),
+        store: Some(store),
+        request_id,
+    }
+}
+
+// which worker handles the messages of a store
+fn shard(store: &str, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    store.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+// turns a failed write into the reply for the socket that attempted it
+fn rejected(
+    e: WriteError,
+    store: String,
+    expected_version: Option<u64>,
+    request_id: Option<u32>,
+) -> ServerMessage {
+    match e {
+        WriteError::Conflict { version } => ServerMessage::Conflict {
+            store,
+            expected_version: expected_version.unwrap_or_default(),
+            version,
+            request_id,
+        },
+        WriteError::StoreNotFound => error(ErrorCode::UnknownStore, store, request_id),
+        WriteError::Invalid(message) => ServerMessage::Error {
+            code: ErrorCode::InvalidValue,
+            message,
+            store: Some(store),
+            request_id,
+        },
+        WriteError::RateLimited => error(ErrorCode::RateLimited, store, request_id),
+        WriteError::TooLarge(limit) => ServerMessage::Error {
+            code: ErrorCode::TooLarge,
+            message: format!("Value exceeds {} bytes", limit),
+            store: Some(store),
+            request_id,
+        },
+        WriteError::TooManyStores => error(ErrorCode::TooManyStores, store, request_id),
+        WriteError::NamespaceNotFound | WriteError::InvalidWriteKey | WriteError::Persist => {
+            error(ErrorCode::Internal, store, request_id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, store::crdt::OpId};
+
+    fn options() -> NamespaceOptions {
+        Config::default().namespace_options()
+    }
+
+    async fn namespace(options: NamespaceOptions) -> Namespace {
+        NamespaceInner::new(
+            "test".to_owned(),
+            Vec::new(),
+            AccessList::default(),
+            None,
+            options,
+        )
+        .await
+    }
+
+    fn write(change: Change, kind: StoreKind) -> Write {
+        Write {
+            change,
+            expected_version: None,
+            kind,
+            author: "test".to_owned(),
+        }
+    }
+
+    fn insert(counter: u64, after: Option<u64>, value: &str) -> Change {
+        let id = |counter| OpId {
+            counter,
+            site: "a".to_owned(),
+        };
+        Change::Crdt(vec![CrdtOp::Insert {
+            id: id(counter),
+            after: after.map(id),
+            value: value.to_owned(),
+        }])
+    }
+
+    #[tokio::test]
+    async fn write_keeps_held_back_crdt_operations() {
+        let ns = namespace(options()).await;
+        let name = "doc".to_owned();
+        let created = Change::Value(Value::Text(String::new()));
+        ns.write(&name, write(created, StoreKind::CrdtText))
+            .await
+            .unwrap();
+
+        // waits for the insert it comes after
+        let held = ns.write(&name, write(insert(2, Some(1), "b"), StoreKind::CrdtText));
+        assert_eq!(held.await.unwrap(), 1);
+
+        let applied = ns.write(&name, write(insert(1, None, "a"), StoreKind::CrdtText));
+        assert_eq!(applied.await.unwrap(), 2);
+
+        let (entry, _) = ns.read_store(&name).await.unwrap();
+        assert_eq!(entry.value, Value::Text("ab".to_owned()));
+    }
+
+    async fn create(ns: &Namespace, name: &str) -> Result<(Store<SocketId>, bool), WriteError> {
+        let text = Value::Text("a".to_owned());
+        ns.create_store(name.to_owned(), text, StoreKind::Text, "test".to_owned())
+            .await
+    }
+
+    #[tokio::test]
+    async fn create_store_keeps_to_max_stores() {
+        let ns = namespace(NamespaceOptions {
+            max_stores: 1,
+            ..options()
+        })
+        .await;
+
+        let (a, b) = tokio::join!(create(&ns, "a"), create(&ns, "b"));
+        assert!(a.is_ok() != b.is_ok());
+    }
+
+    #[tokio::test]
+    async fn create_store_counts_a_store_once() {
+        let ns = namespace(NamespaceOptions {
+            max_stores: 1,
+            ..options()
+        })
+        .await;
+
+        let (first, second) = tokio::join!(create(&ns, "a"), create(&ns, "a"));
+        let (_, first) = first.unwrap();
+        let (_, second) = second.unwrap();
+        assert!(first != second);
+        assert!(matches!(
+            create(&ns, "b").await,
+            Err(WriteError::TooManyStores)
+        ));
     }
 }
@@ -0,0 +1,198 @@
+use std::{
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use tokio::task::AbortHandle;
+
+use crate::ws::{Admission, Admit};
+
+use super::{
+    access::{Capability, Rule, Source},
+    limit::{take_all, Bucket, Strikes},
+    messages::ClientMessage,
+};
+
+// the state of a single socket, shared by all of its messages so that it can
+// change while the socket stays connected, e.g. after an `Auth` message
+pub type Session = Arc<SessionInner>;
+pub struct SessionInner {
+    capability: RwLock<Capability>,
+    // the `Origin` header the socket connected with, if any
+    origin: Option<String>,
+    // the address the socket connected from
+    peer: IpAddr,
+    // rate limits of the socket itself, the keys it used and its namespace
+    socket_limit: Arc<Bucket>,
+    key_limits: RwLock<Vec<Arc<Bucket>>>,
+    namespace_limit: Arc<Bucket>,
+    strikes: Strikes,
+    // the task waiting for the next grant to expire, there is only ever one
+    expiry: Mutex<Option<AbortHandle>>,
+    // messages that arrived while an `Auth` was being checked, `None` while
+    // there is none
+    held: Mutex<Option<Vec<ClientMessage>>>,
+}
+
+pub enum Hold {
+    Held,
+    // too many messages are held back already, the message is handed back
+    Full(ClientMessage),
+    // messages are not held back, the message can be handled right away
+    Pass(ClientMessage),
+}
+
+impl SessionInner {
+    pub fn new(
+        capability: Capability,
+        origin: Option<String>,
+        peer: IpAddr,
+        socket_limit: Arc<Bucket>,
+        namespace_limit: Arc<Bucket>,
+        strikes: Strikes,
+    ) -> Session {
+        Arc::new(Self {
+            capability: RwLock::new(capability),
+            origin,
+            peer,
+            socket_limit,
+            key_limits: RwLock::new(Vec::new()),
+            namespace_limit,
+            strikes,
+            expiry: Mutex::new(None),
+            held: Mutex::new(None),
+        })
+    }
+
+    pub fn origin(&self) -> Option<&str> {
+        self.origin.as_deref()
+    }
+
+    pub fn peer(&self) -> IpAddr {
+        self.peer
+    }
+
+    pub fn can_read(&self, store: &str) -> bool {
+        self.capability
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .can_read(store)
+    }
+
+    pub fn can_write(&self, store: &str) -> bool {
+        self.capability
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .can_write(store)
+    }
+
+    // when the next of the socket's grants expires
+    pub fn expires(&self) -> Option<u64> {
+        self.capability
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .expires()
+    }
+
+    // stops the task waiting for the previous expiry
+    pub fn replace_expiry(&self, task: AbortHandle) {
+        let previous = self
+            .expiry
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .replace(task);
+        if let Some(previous) = previous {
+            previous.abort();
+        }
+    }
+
+    // holds back the socket's messages until `release` finds none left
+    pub fn start_holding(&self) {
+        self.held
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_or_insert_with(Vec::new);
+    }
+
+    // keeps the message for later while an `Auth` is being checked, as long
+    // as fewer than `limit` messages are held back already
+    pub fn hold(&self, message: ClientMessage, limit: usize) -> Hold {
+        match &mut *self.held.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(held) if held.len() >= limit => Hold::Full(message),
+            Some(held) => {
+                held.push(message);
+                Hold::Held
+            }
+            None => Hold::Pass(message),
+        }
+    }
+
+    // the messages held back so far, in order. once there are none left the
+    // socket's messages are no longer held back
+    pub fn release(&self) -> Option<Vec<ClientMessage>> {
+        let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        match held.take() {
+            Some(messages) if !messages.is_empty() => {
+                *held = Some(Vec::new());
+                Some(messages)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn forget_expired(&self) {
+        self.capability
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .forget_expired();
+    }
+
+    pub fn replace_public(&self, public: Capability) {
+        self.capability
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .replace_public(public);
+    }
+
+    pub fn revoke(&self, valid: impl Fn(&Source, &Rule) -> bool) -> bool {
+        self.capability
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .revoke(valid)
+    }
+
+    pub fn limit_keys(&self, limits: Vec<Arc<Bucket>>) {
+        let mut key_limits = self.key_limits.write().unwrap_or_else(|e| e.into_inner());
+        for limit in limits {
+            if !key_limits.iter().any(|l| Arc::ptr_eq(l, &limit)) {
+                key_limits.push(limit);
+            }
+        }
+    }
+
+    pub fn grant(&self, capability: Capability) {
+        self.capability
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend(capability);
+    }
+}
+
+impl Admit for SessionInner {
+    // the narrowest limit is checked first, so a single socket running into
+    // its own limit doesn't use up what its key or namespace may send. only
+    // messages over the socket's own limit count as strikes, a socket held
+    // up by its key or namespace is not disconnected for it
+    fn admit(&self, len: usize) -> Admission {
+        let key_limits = self.key_limits.read().unwrap_or_else(|e| e.into_inner());
+        let mut limits = vec![&*self.socket_limit];
+        limits.extend(key_limits.iter().map(|limit| &**limit));
+        limits.push(&self.namespace_limit);
+
+        match take_all(&limits, len) {
+            Ok(()) => Admission::Allowed,
+            Err(0) if self.strikes.strike() => Admission::Disconnect,
+            Err(_) => Admission::Limited,
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use super::{Backend, Error, NamespaceRecord, StoreRecord};
+
+// stores everything as json files under a single directory:
+//
+// <root>/<hex namespace>/namespace.json
+// <root>/<hex namespace>/stores/<hex store>.json
+//
+// names are hex encoded so that any namespace or store name is a valid file name.
+// names too long for that are replaced by `_<sha256 of the name>`, and the
+// name is kept next to the record in the file instead
+pub struct DiskBackend {
+    root: PathBuf,
+}
+
+impl DiskBackend {
+    pub async fn open(root: impl Into<PathBuf>) -> Result<Self, Error> {
+        let root = root.into();
+        tokio::fs::create_dir_all(&root).await?;
+        Ok(Self { root })
+    }
+
+    fn namespace_dir(&self, namespace: &str) -> PathBuf {
+        self.root.join(encode(namespace))
+    }
+
+    fn stores_dir(&self, namespace: &str) -> PathBuf {
+        self.namespace_dir(namespace).join("stores")
+    }
+
+    fn store_path(&self, namespace: &str, store: &str) -> PathBuf {
+        self.stores_dir(namespace)
+            .join(format!("{}.json", encode(store)))
+    }
+}
+
+impl Backend for DiskBackend {
+    fn load_namespaces(&self) -> BoxFuture<'_, Result<Vec<(String, NamespaceRecord)>, Error>> {
+        async move {
+            let mut namespaces = Vec::new();
+            let mut entries = tokio::fs::read_dir(&self.root).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let Some(file_name) = entry.file_name().to_str().map(str::to_owned) else {
+                    continue;
+                };
+
+                let path = entry.path().join("namespace.json");
+                if let Some(named) = read_named(&path, &file_name).await? {
+                    namespaces.push(named);
+                }
+            }
+
+            Ok(namespaces)
+        }
+        .boxed()
+    }
+
+    fn save_namespace<'a>(
+        &'a self,
+        namespace: &'a str,
+        record: &'a NamespaceRecord,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            // the directories have to be synced as well to survive a power loss
+            tokio::fs::create_dir_all(self.stores_dir(namespace)).await?;
+            sync_dir(&self.root).await?;
+            sync_dir(&self.namespace_dir(namespace)).await?;
+            write_named(
+                &self.namespace_dir(namespace).join("namespace.json"),
+                namespace,
+                record,
+            )
+            .await
+        }
+        .boxed()
+    }
+
+    fn delete_namespace<'a>(&'a self, namespace: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        async move {
+            match tokio::fs::remove_dir_all(self.namespace_dir(namespace)).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        }
+        .boxed()
+    }
+
+    fn load_stores<'a>(
+        &'a self,
+        namespace: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<(String, StoreRecord)>, Error>> {
+        async move {
+            let mut stores = Vec::new();
+            let mut entries = match tokio::fs::read_dir(self.stores_dir(namespace)).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(stores),
+                Err(e) => return Err(e.into()),
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let Some(file_name) = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".json"))
+                    .map(str::to_owned)
+                else {
+                    continue;
+                };
+
+                if let Some(named) = read_named(&entry.path(), &file_name).await? {
+                    stores.push(named);
+                }
+            }
+
+            Ok(stores)
+        }
+        .boxed()
+    }
+
+    fn load_store<'a>(
+        &'a self,
+        namespace: &'a str,
+        store: &'a str,
+    ) -> BoxFuture<'a, Result<Option<StoreRecord>, Error>> {
+        async move {
+            let path = self.store_path(namespace, store);
+            let named = read_named(&path, &encode(store)).await?;
+            Ok(named.and_then(|(name, record)| (name == store).then_some(record)))
+        }
+        .boxed()
+    }
+
+    fn save_store<'a>(
+        &'a self,
+        namespace: &'a str,
+        store: &'a str,
+        record: &'a StoreRecord,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        async move { write_named(&self.store_path(namespace, store), store, record).await }.boxed()
+    }
+}
+
+async fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, Error> {
+    match tokio::fs::read(path).await {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// writes to a temporary file first so a crash never leaves a half written
+// record. the file is synced before it replaces the old one, and the
+// directory after, so the record survives a power loss once this returns
+async fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    let mut file = tokio::fs::File::create(&tmp).await?;
+    file.write_all(&serde_json::to_vec(value)?).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    tokio::fs::rename(&tmp, path).await?;
+    if let Some(dir) = path.parent() {
+        sync_dir(dir).await?;
+    }
+    Ok(())
+}
+
+async fn sync_dir(path: &Path) -> Result<(), Error> {
+    tokio::fs::File::open(path).await?.sync_all().await?;
+    Ok(())
+}
+
+// the record of a name that was too long for its file name
+#[derive(Serialize, Deserialize)]
+struct Named<T> {
+    name: String,
+    record: T,
+}
+
+// reads the record in `path` together with its name, which is either
+// encoded in `file_name` or stored in the file
+async fn read_named<T: DeserializeOwned>(
+    path: &Path,
+    file_name: &str,
+) -> Result<Option<(String, T)>, Error> {
+    if let Some(name) = decode(file_name) {
+        let record = read_json(path).await?;
+        return Ok(record.map(|record| (name, record)));
+    }
+    if !file_name.starts_with(HASHED) {
+        return Ok(None);
+    }
+
+    let named: Option<Named<T>> = read_json(path).await?;
+    Ok(named.map(|named| (named.name, named.record)))
+}
+
+async fn write_named<T: Serialize>(path: &Path, name: &str, record: &T) -> Result<(), Error> {
+    if !encode(name).starts_with(HASHED) {
+        return write_json(path, record).await;
+    }
+
+    let named = Named {
+        name: name.to_owned(),
+        record,
+    };
+    write_json(path, &named).await
+}
+
+// file names are limited to 255 bytes on most file systems, this leaves room
+// for `.json`. every name that fit before keeps its file
+const MAX_ENCODED: usize = 250;
+const HASHED: char = '_';
+
+fn encode(name: &str) -> String {
+    let encoded = hex(name.as_bytes());
+    if encoded.len() <= MAX_ENCODED {
+        return encoded;
+    }
+    format!("{}{}", HASHED, hex(&Sha256::digest(name.as_bytes())))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode(name: &str) -> Option<String> {
+    if !name.is_ascii() || !name.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&name[i..i + 2], 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{StoreKind, Value};
+
+    // a directory of its own for every test, removed again by the test
+    fn root(test: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("store-disk-{}-{}", std::process::id(), test))
+    }
+
+    fn record(text: &str) -> StoreRecord {
+        StoreRecord {
+            value: Value::Text(text.to_owned()),
+            compressed: None,
+            version: 3,
+            kind: StoreKind::Text,
+            crdt: None,
+        }
+    }
+
+    #[test]
+    fn names_round_trip_through_their_file_names() {
+        for name in ["a", "game/board", "../..", "ünïcödé", ""] {
+            assert_eq!(decode(&encode(name)).as_deref(), Some(name));
+        }
+        assert_eq!(encode("ab"), "6162");
+    }
+
+    #[test]
+    fn long_names_are_hashed() {
+        let name = "a".repeat(MAX_ENCODED / 2 + 1);
+        let encoded = encode(&name);
+        assert!(encoded.starts_with(HASHED));
+        assert!(encoded.len() <= MAX_ENCODED);
+        assert_eq!(decode(&encoded), None);
+
+        // the longest name that still fits is kept as it is
+        let name = "a".repeat(MAX_ENCODED / 2);
+        assert_eq!(decode(&encode(&name)), Some(name));
+    }
+
+    #[test]
+    fn decode_rejects_what_encode_does_not_produce() {
+        assert_eq!(decode("616"), None);
+        assert_eq!(decode("zz"), None);
+        assert_eq!(decode("ff"), None);
+    }
+
+    #[tokio::test]
+    async fn hashed_names_round_trip() {
+        let root = root("hashed");
+        let backend = DiskBackend::open(&root).await.unwrap();
+        let namespace = "n".repeat(200);
+        let store = "s".repeat(200);
+        let other = "t".repeat(200);
+
+        let namespace_record = NamespaceRecord {
+            write_key: None,
+            write_keys: vec!["hash".to_owned()],
+            access: Default::default(),
+            configured: true,
+        };
+        backend
+            .save_namespace(&namespace, &namespace_record)
+            .await
+            .unwrap();
+        backend
+            .save_store(&namespace, &store, &record("value"))
+            .await
+            .unwrap();
+
+        let namespaces = backend.load_namespaces().await.unwrap();
+        assert_eq!(namespaces.len(), 1);
+        assert_eq!(namespaces[0].0, namespace);
+        assert_eq!(namespaces[0].1.write_keys, vec!["hash".to_owned()]);
+
+        let loaded = backend.load_store(&namespace, &store).await.unwrap();
+        assert_eq!(loaded.unwrap().value, Value::Text("value".to_owned()));
+        assert!(backend
+            .load_store(&namespace, &other)
+            .await
+            .unwrap()
+            .is_none());
+
+        let stores = backend.load_stores(&namespace).await.unwrap();
+        assert_eq!(stores.len(), 1);
+        assert_eq!(stores[0].0, store);
+
+        backend.delete_namespace(&namespace).await.unwrap();
+        assert!(backend.load_namespaces().await.unwrap().is_empty());
+        tokio::fs::remove_dir_all(&root).await.unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+mod disk;
+
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    namespace::access::AccessList,
+    store::{blob::Bytes, crdt::Crdt, StoreKind, Value},
+};
+
+pub use disk::DiskBackend;
+
+pub type Persistence = Arc<dyn Backend>;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NamespaceRecord {
+    // the cleartext key of records written before keys were hashed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_key: Option<String>,
+    // argon2 hashes
+    #[serde(default)]
+    pub write_keys: Vec<String>,
+    #[serde(default)]
+    pub access: AccessList,
+    // whether the namespace comes from the config file, it is only restored
+    // while it is still in there
+    #[serde(default)]
+    pub configured: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoreRecord {
+    // a plain string for everything but blobs, as written by older versions.
+    // empty while the value is compressed
+    pub value: Value,
+    // the value as json, compressed with zstd
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compressed: Option<Bytes>,
+    #[serde(default = "first_version")]
+    pub version: u64,
+    #[serde(default)]
+    pub kind: StoreKind,
+    #[serde(default)]
+    pub crdt: Option<Crdt>,
+}
+
+fn first_version() -> u64 {
+    1
+}
+
+// values larger than `above` bytes are compressed with zstd before they are
+// saved, 0 never compresses
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Compression {
+    pub above: usize,
+    pub level: i32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self { above: 0, level: 3 }
+    }
+}
+
+impl StoreRecord {
+    // the record is left as it is if compressing fails or does not make the
+    // value any smaller, e.g. for blobs that are compressed already
+    pub fn compress(&mut self, compression: Compression) -> Result<(), Error> {
+        if compression.above == 0 || self.value.size() <= compression.above {
+            return Ok(());
+        }
+
+        let json = serde_json::to_vec(&self.value)?;
+        let compressed = zstd::bulk::compress(&json, compression.level)?;
+        if compressed.len() < json.len() {
+            self.value = Value::Text(String::new());
+            self.compressed = Some(Bytes(compressed));
+        }
+        Ok(())
+    }
+
+    pub fn decompress(&mut self) -> Result<(), Error> {
+        let Some(compressed) = &self.compressed else {
+            return Ok(());
+        };
+
+        let json = zstd::stream::decode_all(&compressed.0[..])?;
+        self.value = serde_json::from_slice(&json)?;
+        self.compressed = None;
+        Ok(())
+    }
+}
+
+// a place namespaces and their stores are written through to, so that they
+// survive restarts and cache eviction
+pub trait Backend: Send + Sync {
+    fn load_namespaces(&self) -> BoxFuture<'_, Result<Vec<(String, NamespaceRecord)>, Error>>;
+
+    fn save_namespace<'a>(
+        &'a self,
+        namespace: &'a str,
+        record: &'a NamespaceRecord,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn delete_namespace<'a>(&'a self, namespace: &'a str) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn load_stores<'a>(
+        &'a self,
+        namespace: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<(String, StoreRecord)>, Error>>;
+
+    fn load_store<'a>(
+        &'a self,
+        namespace: &'a str,
+        store: &'a str,
+    ) -> BoxFuture<'a, Result<Option<StoreRecord>, Error>>;
+
+    fn save_store<'a>(
+        &'a self,
+        namespace: &'a str,
+        store: &'a str,
+        record: &'a StoreRecord,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
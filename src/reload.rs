@@ -0,0 +1,74 @@
+use std::{path::Path, time::Duration, time::SystemTime};
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    app::App,
+    config::{Cli, Config},
+    log,
+};
+
+// how often the config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// reloads the config file whenever it changes or the process receives a
+// SIGHUP. namespaces, their keys and the log level are applied to the running
+// server, everything else needs a restart
+pub fn watch(app: App, cli: Cli, config: Config) {
+    let Some(path) = cli.config.clone() else {
+        return;
+    };
+
+    tokio::task::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("Error listening for SIGHUP: {}", e);
+                return;
+            }
+        };
+
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        let mut modified = modified(&path);
+        let mut current = config;
+        loop {
+            let reload = tokio::select! {
+                _ = hangup.recv() => {
+                    info!("Received SIGHUP, reloading config");
+                    true
+                }
+                _ = interval.tick() => {
+                    let previous = std::mem::replace(&mut modified, self::modified(&path));
+                    previous != modified
+                }
+            };
+            if !reload {
+                continue;
+            }
+
+            let next = match Config::load(cli.clone()) {
+                Ok(next) => next,
+                Err(e) => {
+                    error!("Keeping the current config: {}", e);
+                    continue;
+                }
+            };
+
+            log::set_level(next.log_level);
+            app.reconfigure(&current.namespaces, next.namespaces.clone())
+                .await;
+            if current.needs_restart(&next) {
+                warn!(
+                    "Reloaded config, changes outside of namespaces and log_level need a restart"
+                );
+            } else {
+                info!("Reloaded config");
+            }
+            current = next;
+        }
+    });
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
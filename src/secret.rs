@@ -0,0 +1,86 @@
+use std::sync::OnceLock;
+
+use argon2::{
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        PasswordHash, SaltString,
+    },
+    Argon2, PasswordHasher, PasswordVerifier,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// keys are only ever kept as salted argon2 hashes in the phc string format,
+// e.g. `$argon2id$v=19$...`
+pub fn hash(key: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(key.as_bytes(), &salt)
+        .expect("failed to hash key")
+        .to_string()
+}
+
+pub fn is_hash(value: &str) -> bool {
+    PasswordHash::new(value).is_ok()
+}
+
+// hashes `value` unless it already is a hash, so hashes can be configured directly
+pub fn hashed(value: String) -> String {
+    if is_hash(&value) {
+        return value;
+    }
+    hash(&value)
+}
+
+// like `hashed`, but keeps the previous hash of keys that did not change, so
+// that whoever authenticated with them keeps their access
+pub fn rehashed(value: String, previous: &[String]) -> String {
+    if is_hash(&value) {
+        return value;
+    }
+
+    match previous.iter().find(|hash| verify(&value, hash)) {
+        Some(hash) => hash.clone(),
+        None => hash(&value),
+    }
+}
+
+// compares in constant time, a malformed hash never matches
+pub fn verify(key: &str, hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return false;
+    };
+    Argon2::default()
+        .verify_password(key.as_bytes(), &hash)
+        .is_ok()
+}
+
+// identifies a key without keeping it around, for caching verifications. the
+// mac is keyed with a random secret that only lives as long as the process, so
+// the digests of cached keys can't be used to guess them
+pub fn digest(key: &str) -> [u8; 32] {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(process_secret()).expect("hmac takes keys of any size");
+    mac.update(key.as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+fn process_secret() -> &'static [u8; 32] {
+    static SECRET: OnceLock<[u8; 32]> = OnceLock::new();
+    SECRET.get_or_init(|| {
+        let mut secret = [0; 32];
+        OsRng.fill_bytes(&mut secret);
+        secret
+    })
+}
+
+// argon2 is slow on purpose, so it runs off the async workers
+pub async fn blocking<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .expect("failed to run blocking task")
+}
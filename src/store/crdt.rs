@@ -0,0 +1,540 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use specta::Type;
+
+use super::StoreKind;
+
+// identifies an operation. `counter` is a lamport clock, so it must be larger
+// than every counter the site has seen, and `site` is unique per client.
+// ids are ordered by counter first and site second
+#[derive(Type, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct OpId {
+    pub counter: u64,
+    pub site: String,
+}
+
+#[derive(Type, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "op")]
+pub enum CrdtOp {
+    // inserts a character after `after`, or at the start if it is null
+    Insert {
+        id: OpId,
+        after: Option<OpId>,
+        value: String,
+    },
+    Delete {
+        id: OpId,
+    },
+
+    // sets `key`, replacing the values tagged with the observed `removes`
+    Put {
+        key: String,
+        value: Value,
+        tag: OpId,
+        removes: Vec<OpId>,
+    },
+    // removes the observed `tags` of `key`
+    Remove {
+        key: String,
+        tags: Vec<OpId>,
+    },
+}
+
+// how many operations a text may hold back while they wait for what they
+// depend on
+pub const MAX_PENDING: usize = 1024;
+
+#[derive(Type, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum Crdt {
+    Text(Rga),
+    Map(OrMap),
+}
+
+impl Crdt {
+    // seeds the state from a plain value, a string for text and a json
+    // object for maps. the seed is attributed to the server's empty site
+    pub fn seed(kind: StoreKind, value: &str) -> Option<Crdt> {
+        let site = String::new();
+        match kind {
+            StoreKind::CrdtText => {
+                let mut rga = Rga::default();
+                for (counter, c) in value.chars().enumerate() {
+                    rga.elements.push(RgaElement {
+                        id: OpId {
+                            counter: counter as u64 + 1,
+                            site: site.clone(),
+                        },
+                        value: c.to_string(),
+                        deleted: false,
+                    });
+                }
+                Some(Crdt::Text(rga))
+            }
+            StoreKind::CrdtMap => {
+                let mut map = OrMap::default();
+                if let Ok(Value::Object(object)) = serde_json::from_str(value) {
+                    for (key, value) in object {
+                        let tag = OpId {
+                            counter: 1,
+                            site: site.clone(),
+                        };
+                        map.entries.insert(key, vec![MapEntry { tag, value }]);
+                    }
+                }
+                Some(Crdt::Map(map))
+            }
+            StoreKind::Text | StoreKind::Json | StoreKind::Blob => None,
+        }
+    }
+
+    // applies operations in any order, returning the ones that changed the
+    // state. operations that depend on something not seen yet are held back
+    // until it arrives, operations that were already applied are ignored
+    pub fn apply(&mut self, ops: Vec<CrdtOp>) -> Result<Vec<CrdtOp>, String> {
+        // a batch with an invalid operation is rejected before any of it was
+        // applied, text is inserted one character at a time
+        for op in &ops {
+            if let CrdtOp::Insert { value, .. } = op {
+                if value.chars().count() != 1 {
+                    return Err("Inserts hold a single character".into());
+                }
+            }
+
+            let text = matches!(op, CrdtOp::Insert { .. } | CrdtOp::Delete { .. });
+            match self {
+                Crdt::Text(_) if !text => {
+                    return Err("Map operations cannot be applied to text".into())
+                }
+                Crdt::Map(_) if text => {
+                    return Err("Text operations cannot be applied to a map".into())
+                }
+                _ => (),
+            }
+        }
+
+        match self {
+            Crdt::Text(rga) => rga.apply(ops),
+            Crdt::Map(map) => map.apply(ops),
+        }
+    }
+
+    // how many operations are held back
+    pub fn pending(&self) -> usize {
+        match self {
+            Crdt::Text(rga) => rga.pending.len(),
+            Crdt::Map(_) => 0,
+        }
+    }
+
+    // the plain value the state stands for, a string or a json object
+    pub fn materialize(&self) -> String {
+        match self {
+            Crdt::Text(rga) => rga.materialize(),
+            Crdt::Map(map) => map.materialize(),
+        }
+    }
+
+    // in bytes, as the state is sent to subscribers and persisted. tombstones
+    // and held back operations count towards it
+    pub fn size(&self) -> usize {
+        let mut counter = Counter(0);
+        match serde_json::to_writer(&mut counter, self) {
+            Ok(()) => counter.0,
+            Err(_) => 0,
+        }
+    }
+}
+
+// counts what is written to it
+struct Counter(usize);
+
+impl io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Type, Clone, Debug, Serialize, Deserialize)]
+pub struct RgaElement {
+    pub id: OpId,
+    pub value: String,
+    pub deleted: bool,
+}
+
+// replicated growable array, deleted elements stay behind as tombstones so
+// that later operations can still refer to them
+#[derive(Type, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Rga {
+    pub elements: Vec<RgaElement>,
+    pub pending: Vec<CrdtOp>,
+}
+
+impl Rga {
+    fn position(&self, id: &OpId) -> Option<usize> {
+        self.elements.iter().position(|element| element.id == *id)
+    }
+
+    fn integrate(&mut self, op: &CrdtOp) -> Result<Option<bool>, String> {
+        match op {
+            CrdtOp::Insert { id, after, value } => {
+                if self.position(id).is_some() {
+                    return Ok(Some(false));
+                }
+
+                let mut index = match after {
+                    Some(after) => match self.position(after) {
+                        Some(position) => position + 1,
+                        None => return Ok(None),
+                    },
+                    None => 0,
+                };
+
+                // concurrent inserts at the same place are ordered by id,
+                // the largest id comes first
+                while index < self.elements.len() && self.elements[index].id > *id {
+                    index += 1;
+                }
+
+                self.elements.insert(
+                    index,
+                    RgaElement {
+                        id: id.clone(),
+                        value: value.clone(),
+                        deleted: false,
+                    },
+                );
+                Ok(Some(true))
+            }
+            CrdtOp::Delete { id } => match self.position(id) {
+                Some(position) => {
+                    let element = &mut self.elements[position];
+                    let changed = !element.deleted;
+                    element.deleted = true;
+                    Ok(Some(changed))
+                }
+                None => Ok(None),
+            },
+            CrdtOp::Put { .. } | CrdtOp::Remove { .. } => {
+                Err("Map operations cannot be applied to text".into())
+            }
+        }
+    }
+
+    // fails once more than `MAX_PENDING` operations are held back, the state
+    // is left partly applied then
+    fn apply(&mut self, ops: Vec<CrdtOp>) -> Result<Vec<CrdtOp>, String> {
+        let mut applied = Vec::new();
+        for op in ops {
+            match self.integrate(&op)? {
+                Some(true) => applied.push(op),
+                Some(false) => (),
+                None => self.pending.push(op),
+            }
+        }
+        if self.pending.len() > MAX_PENDING {
+            return Err(format!(
+                "More than {} operations are waiting for what they refer to",
+                MAX_PENDING
+            ));
+        }
+
+        // anything applied may have been what a pending operation waited for
+        let mut progress = !applied.is_empty();
+        while progress {
+            progress = false;
+            for op in std::mem::take(&mut self.pending) {
+                match self.integrate(&op)? {
+                    Some(true) => {
+                        applied.push(op);
+                        progress = true;
+                    }
+                    Some(false) => (),
+                    None => self.pending.push(op),
+                }
+            }
+        }
+
+        Ok(applied)
+    }
+
+    fn materialize(&self) -> String {
+        self.elements
+            .iter()
+            .filter(|element| !element.deleted)
+            .map(|element| element.value.as_str())
+            .collect()
+    }
+}
+
+#[derive(Type, Clone, Debug, Serialize, Deserialize)]
+pub struct MapEntry {
+    pub tag: OpId,
+    pub value: Value,
+}
+
+// observed-remove map, concurrent puts to the same key are kept side by side
+// and the one with the largest tag wins. a put concurrent with a remove wins
+#[derive(Type, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OrMap {
+    pub entries: BTreeMap<String, Vec<MapEntry>>,
+    pub removed: BTreeSet<OpId>,
+}
+
+impl OrMap {
+    fn remove_tags(&mut self, key: &str, tags: &[OpId]) -> bool {
+        let mut changed = false;
+        for tag in tags {
+            changed |= self.removed.insert(tag.clone());
+        }
+
+        if let Some(entries) = self.entries.get_mut(key) {
+            entries.retain(|entry| !tags.contains(&entry.tag));
+            if entries.is_empty() {
+                self.entries.remove(key);
+            }
+        }
+
+        changed
+    }
+
+    fn apply(&mut self, ops: Vec<CrdtOp>) -> Result<Vec<CrdtOp>, String> {
+        let mut applied = Vec::new();
+        for op in ops {
+            let changed = match &op {
+                CrdtOp::Put {
+                    key,
+                    value,
+                    tag,
+                    removes,
+                } => {
+                    let mut changed = self.remove_tags(key, removes);
+
+                    let entries = self.entries.entry(key.clone()).or_default();
+                    if !self.removed.contains(tag) && entries.iter().all(|e| e.tag != *tag) {
+                        entries.push(MapEntry {
+                            tag: tag.clone(),
+                            value: value.clone(),
+                        });
+                        changed = true;
+                    }
+
+                    if entries.is_empty() {
+                        self.entries.remove(key);
+                    }
+                    changed
+                }
+                CrdtOp::Remove { key, tags } => self.remove_tags(key, tags),
+                CrdtOp::Insert { .. } | CrdtOp::Delete { .. } => {
+                    return Err("Text operations cannot be applied to a map".into())
+                }
+            };
+
+            if changed {
+                applied.push(op);
+            }
+        }
+
+        Ok(applied)
+    }
+
+    fn materialize(&self) -> String {
+        let object: serde_json::Map<String, Value> = self
+            .entries
+            .iter()
+            .filter_map(|(key, entries)| {
+                let winner = entries.iter().max_by(|a, b| a.tag.cmp(&b.tag))?;
+                Some((key.clone(), winner.value.clone()))
+            })
+            .collect();
+
+        Value::Object(object).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(counter: u64, site: &str) -> OpId {
+        OpId {
+            counter,
+            site: site.to_owned(),
+        }
+    }
+
+    fn insert(id: OpId, after: Option<OpId>, value: &str) -> CrdtOp {
+        CrdtOp::Insert {
+            id,
+            after,
+            value: value.to_owned(),
+        }
+    }
+
+    fn put(key: &str, value: Value, tag: OpId, removes: Vec<OpId>) -> CrdtOp {
+        CrdtOp::Put {
+            key: key.to_owned(),
+            value,
+            tag,
+            removes,
+        }
+    }
+
+    // applies every batch to a fresh replica in the given order
+    fn replay(kind: StoreKind, seed: &str, batches: &[Vec<CrdtOp>]) -> Crdt {
+        let mut crdt = Crdt::seed(kind, seed).unwrap();
+        for batch in batches {
+            crdt.apply(batch.clone()).unwrap();
+        }
+        crdt
+    }
+
+    #[test]
+    fn rga_converges_on_concurrent_inserts() {
+        let a = vec![
+            insert(id(1, "a"), None, "a"),
+            insert(id(2, "a"), Some(id(1, "a")), "b"),
+        ];
+        let b = vec![
+            insert(id(1, "b"), None, "x"),
+            insert(id(2, "b"), Some(id(1, "b")), "y"),
+        ];
+
+        let ab = replay(StoreKind::CrdtText, "", &[a.clone(), b.clone()]);
+        let ba = replay(StoreKind::CrdtText, "", &[b, a]);
+        assert_eq!(ab.materialize(), ba.materialize());
+        assert_eq!(ab.materialize(), "xyab");
+    }
+
+    #[test]
+    fn rga_holds_back_operations_until_they_apply() {
+        let mut crdt = Crdt::seed(StoreKind::CrdtText, "").unwrap();
+
+        let applied = crdt
+            .apply(vec![
+                CrdtOp::Delete { id: id(2, "a") },
+                insert(id(2, "a"), Some(id(1, "a")), "b"),
+            ])
+            .unwrap();
+        assert!(applied.is_empty());
+        assert_eq!(crdt.materialize(), "");
+
+        let applied = crdt.apply(vec![insert(id(1, "a"), None, "a")]).unwrap();
+        assert_eq!(applied.len(), 3);
+        assert_eq!(crdt.materialize(), "a");
+    }
+
+    #[test]
+    fn rga_bounds_held_back_operations() {
+        let mut crdt = Crdt::seed(StoreKind::CrdtText, "").unwrap();
+        let missing = id(1, "missing");
+
+        let ops: Vec<CrdtOp> = (0..MAX_PENDING as u64)
+            .map(|counter| insert(id(counter + 2, "a"), Some(missing.clone()), "a"))
+            .collect();
+        assert!(crdt.apply(ops).is_ok());
+
+        let op = insert(id(1, "b"), Some(missing), "b");
+        assert!(crdt.apply(vec![op]).is_err());
+    }
+
+    #[test]
+    fn rga_rejects_inserts_of_several_characters() {
+        let mut crdt = Crdt::seed(StoreKind::CrdtText, "").unwrap();
+
+        assert!(crdt.apply(vec![insert(id(1, "a"), None, "ab")]).is_err());
+        assert!(crdt.apply(vec![insert(id(1, "a"), None, "")]).is_err());
+        assert!(crdt.apply(vec![insert(id(1, "a"), None, "é")]).is_ok());
+        assert_eq!(crdt.materialize(), "é");
+    }
+
+    #[test]
+    fn rga_ignores_duplicates() {
+        let mut crdt = Crdt::seed(StoreKind::CrdtText, "ab").unwrap();
+        let op = insert(id(3, "a"), Some(id(2, "")), "c");
+
+        assert_eq!(crdt.apply(vec![op.clone()]).unwrap().len(), 1);
+        assert!(crdt.apply(vec![op]).unwrap().is_empty());
+        assert_eq!(crdt.materialize(), "abc");
+    }
+
+    #[test]
+    fn rga_rejects_mixed_batches_without_applying_them() {
+        let mut crdt = Crdt::seed(StoreKind::CrdtText, "ab").unwrap();
+
+        let result = crdt.apply(vec![
+            insert(id(3, "a"), None, "Z"),
+            put("key", Value::Null, id(4, "a"), vec![]),
+        ]);
+        assert!(result.is_err());
+        assert_eq!(crdt.materialize(), "ab");
+    }
+
+    #[test]
+    fn or_map_converges_on_concurrent_puts() {
+        let a = vec![put("key", Value::from(1), id(1, "a"), vec![])];
+        let b = vec![put("key", Value::from(2), id(1, "b"), vec![])];
+
+        let ab = replay(StoreKind::CrdtMap, "{}", &[a.clone(), b.clone()]);
+        let ba = replay(StoreKind::CrdtMap, "{}", &[b, a]);
+        assert_eq!(ab.materialize(), ba.materialize());
+        assert_eq!(ab.materialize(), r#"{"key":2}"#);
+    }
+
+    #[test]
+    fn or_map_put_wins_over_concurrent_remove() {
+        let seed = vec![put("key", Value::from(1), id(1, "a"), vec![])];
+        let remove = vec![CrdtOp::Remove {
+            key: "key".to_owned(),
+            tags: vec![id(1, "a")],
+        }];
+        let replace = vec![put("key", Value::from(2), id(2, "b"), vec![id(1, "a")])];
+
+        let first = replay(
+            StoreKind::CrdtMap,
+            "{}",
+            &[seed.clone(), remove.clone(), replace.clone()],
+        );
+        let second = replay(StoreKind::CrdtMap, "{}", &[seed, replace, remove]);
+        assert_eq!(first.materialize(), second.materialize());
+        assert_eq!(first.materialize(), r#"{"key":2}"#);
+    }
+
+    #[test]
+    fn or_map_ignores_removed_tags() {
+        let mut crdt = Crdt::seed(StoreKind::CrdtMap, "{}").unwrap();
+        crdt.apply(vec![CrdtOp::Remove {
+            key: "key".to_owned(),
+            tags: vec![id(1, "a")],
+        }])
+        .unwrap();
+
+        let applied = crdt
+            .apply(vec![put("key", Value::from(1), id(1, "a"), vec![])])
+            .unwrap();
+        assert!(applied.is_empty());
+        assert_eq!(crdt.materialize(), "{}");
+    }
+
+    #[test]
+    fn or_map_rejects_mixed_batches_without_applying_them() {
+        let mut crdt = Crdt::seed(StoreKind::CrdtMap, r#"{"key":1}"#).unwrap();
+
+        let result = crdt.apply(vec![
+            put("key", Value::from(2), id(2, "a"), vec![id(1, "")]),
+            insert(id(3, "a"), None, "Z"),
+        ]);
+        assert!(result.is_err());
+        assert_eq!(crdt.materialize(), r#"{"key":1}"#);
+    }
+}
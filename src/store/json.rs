@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use specta::Type;
+
+// a single RFC 6902 operation
+#[derive(Type, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOp {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+#[derive(Clone, Debug)]
+pub enum JsonChange {
+    // RFC 6902 json patch
+    Patch(Vec<PatchOp>),
+    // RFC 7386 json merge patch
+    Merge(Value),
+}
+
+pub fn validate(value: &str) -> Result<(), String> {
+    serde_json::from_str::<Value>(value)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// applies the change to a serialized document, returning the new document.
+// a json patch is applied atomically, if any operation fails nothing changes
+pub fn apply(document: &str, change: &JsonChange) -> Result<String, String> {
+    let mut document: Value = serde_json::from_str(document).map_err(|e| e.to_string())?;
+
+    match change {
+        JsonChange::Patch(ops) => {
+            let ops = serde_json::to_value(ops).map_err(|e| e.to_string())?;
+            let patch: json_patch::Patch =
+                serde_json::from_value(ops).map_err(|e| e.to_string())?;
+            json_patch::patch(&mut document, &patch).map_err(|e| e.to_string())?;
+        }
+        JsonChange::Merge(patch) => json_patch::merge(&mut document, patch),
+    }
+
+    serde_json::to_string(&document).map_err(|e| e.to_string())
+}
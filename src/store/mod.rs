@@ -1,15 +1,166 @@
+pub mod blob;
+pub mod crdt;
+pub mod json;
 mod unique;
 
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use specta::Type;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{RwLock, RwLockWriteGuard};
+
+use blob::{Bytes, OCTET_STREAM};
+use crdt::Crdt;
 use unique::Unique;
 
+#[derive(Type, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StoreKind {
+    // an opaque string
+    #[default]
+    Text,
+    // a json document, which can be patched
+    Json,
+    // collaborative text and objects, changed through crdt operations
+    CrdtText,
+    CrdtMap,
+    // raw bytes with a content type, e.g. images
+    Blob,
+}
+
+impl StoreKind {
+    pub fn is_crdt(self) -> bool {
+        matches!(self, StoreKind::CrdtText | StoreKind::CrdtMap)
+    }
+}
+
+const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+
+// the value of a store. blob stores hold bytes and the content type they were
+// written with, every other kind holds text. text is sent as a plain string,
+// so clients that never use blobs see no difference
+#[derive(Type, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Text(String),
+    Blob {
+        content_type: String,
+        #[specta(type = String)]
+        data: Bytes,
+    },
+}
+
+impl Value {
+    // in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Value::Text(text) => text.len(),
+            Value::Blob { data, .. } => data.0.len(),
+        }
+    }
+
+    // the text of text values, blobs have none
+    pub fn text(&self) -> &str {
+        match self {
+            Value::Text(text) => text,
+            Value::Blob { .. } => "",
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Value::Text(text) => text.into_bytes(),
+            Value::Blob { data, .. } => data.0,
+        }
+    }
+
+    pub fn content_type(&self, kind: StoreKind) -> &str {
+        match (self, kind) {
+            (Value::Blob { content_type, .. }, _) => content_type,
+            (_, StoreKind::Json | StoreKind::CrdtMap) => "application/json",
+            (_, _) => TEXT_PLAIN,
+        }
+    }
+
+    // the value as a store of `kind` holds it. text written to a blob store
+    // becomes a blob, and blobs of text, e.g. `text/html`, written to other
+    // stores become text. other blobs can not be written to other stores
+    pub fn into_kind(self, kind: StoreKind) -> Result<Value, String> {
+        match (self, kind) {
+            // e.g. the `initial` of a blob store, which has no type yet
+            (Value::Text(text), StoreKind::Blob) if text.is_empty() => Ok(Value::Blob {
+                content_type: OCTET_STREAM.into(),
+                data: Bytes::default(),
+            }),
+            (Value::Text(text), StoreKind::Blob) => Ok(Value::Blob {
+                content_type: TEXT_PLAIN.into(),
+                data: Bytes(text.into_bytes()),
+            }),
+            (Value::Blob { content_type, data }, StoreKind::Blob) => Ok(Value::Blob {
+                content_type: if content_type.is_empty() {
+                    OCTET_STREAM.into()
+                } else {
+                    content_type
+                },
+                data,
+            }),
+            (Value::Text(text), _) => Ok(Value::Text(text)),
+            (Value::Blob { content_type, data }, _) if content_type.starts_with("text/") => {
+                String::from_utf8(data.0)
+                    .map(Value::Text)
+                    .map_err(|_| "Text is not valid utf-8".into())
+            }
+            (Value::Blob { .. }, _) => Err("Store does not hold blobs".into()),
+        }
+    }
+}
+
+impl From<String> for Value {
+    fn from(text: String) -> Self {
+        Value::Text(text)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub value: Value,
+    // bumped on every write, a store starts at version 1
+    pub version: u64,
+}
+
+// how many past values every store keeps around
+pub const HISTORY_LENGTH: usize = 64;
+
+#[derive(Type, Clone, Debug, Serialize)]
+pub struct Revision {
+    pub version: u64,
+    pub value: Value,
+    // milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub author: Option<String>,
+}
+
 pub type Store<S> = Arc<StoreInner<S>>;
 pub struct StoreInner<S>
 where
     S: std::hash::Hash + Eq + Clone,
 {
-    data: RwLock<String>,
+    kind: StoreKind,
+    data: RwLock<Entry>,
+    // the state behind the value of crdt stores, only locked while holding
+    // the entry lock
+    crdt: Option<Mutex<Crdt>>,
+    // the latest revisions, oldest first
+    history: Mutex<VecDeque<Revision>>,
+    // the serialized size of the crdt state
+    crdt_size: AtomicUsize,
+    // roughly the bytes held by the value, its history and the crdt state
+    weight: AtomicUsize,
     subscribers: RwLock<Unique<S>>,
 }
 
@@ -17,19 +168,148 @@ impl<S> StoreInner<S>
 where
     S: std::hash::Hash + Eq + Clone,
 {
-    pub fn new(inital: String) -> Store<S> {
+    // crdt stores without a `crdt` state are seeded from the initial value
+    pub fn new(inital: Value, version: u64, kind: StoreKind, crdt: Option<Crdt>) -> Store<S> {
+        let crdt = crdt.or_else(|| Crdt::seed(kind, inital.text()));
+        let value = match &crdt {
+            Some(crdt) => Value::Text(crdt.materialize()),
+            None => inital,
+        };
+
+        let crdt_size = crdt.as_ref().map_or(0, Crdt::size);
+
         Arc::new(Self {
-            data: RwLock::new(inital),
+            kind,
+            crdt_size: AtomicUsize::new(crdt_size),
+            weight: AtomicUsize::new(value.size() + crdt_size),
+            data: RwLock::new(Entry { value, version }),
+            crdt: crdt.map(Mutex::new),
+            history: Mutex::new(VecDeque::new()),
             subscribers: RwLock::new(Unique::new()),
         })
     }
 
-    pub async fn get(&self) -> String {
+    pub fn kind(&self) -> StoreKind {
+        self.kind
+    }
+
+    // for weighing the store cache, which counts in u32
+    pub fn weight(&self) -> u32 {
+        self.weight
+            .load(Ordering::Relaxed)
+            .try_into()
+            .unwrap_or(u32::MAX)
+    }
+
+    pub async fn entry(&self) -> Entry {
         self.data.read().await.clone()
     }
 
-    pub async fn set(&self, value: String) {
-        *self.data.write().await = value;
+    // holding the guard serializes writers, e.g. while a write is persisted
+    pub async fn lock(&self) -> RwLockWriteGuard<'_, Entry> {
+        self.data.write().await
+    }
+
+    // the entry together with the crdt state it was materialized from
+    pub async fn snapshot(&self) -> (Entry, Option<Crdt>) {
+        let entry = self.data.read().await;
+        let crdt = self.crdt().map(|crdt| crdt.clone());
+        (entry.clone(), crdt)
+    }
+
+    pub fn crdt(&self) -> Option<MutexGuard<'_, Crdt>> {
+        let crdt = self.crdt.as_ref()?;
+        Some(crdt.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn crdt_size(&self) -> usize {
+        self.crdt_size.load(Ordering::Relaxed)
+    }
+
+    // swaps in the state a write produced along with its size, call while
+    // holding the entry lock
+    pub fn replace_crdt(&self, next: Crdt, size: usize) {
+        if let Some(mut crdt) = self.crdt() {
+            *crdt = next;
+            let previous = self.crdt_size.swap(size, Ordering::Relaxed);
+            self.weight.fetch_add(size, Ordering::Relaxed);
+            self.weight.fetch_sub(previous, Ordering::Relaxed);
+        }
+    }
+
+    // remembers the entry as a revision, call while holding the entry lock.
+    // the oldest revisions are dropped once the store weighs more than `budget`
+    pub fn record(&self, entry: &Entry, author: Option<String>, budget: usize) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        let mut history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        if history.len() == HISTORY_LENGTH {
+            history.pop_front();
+        }
+        history.push_back(Revision {
+            version: entry.version,
+            value: entry.value.clone(),
+            timestamp,
+            author,
+        });
+
+        let mut weight = entry.value.size()
+            + history.iter().map(|r| r.value.size()).sum::<usize>()
+            + self.crdt_size();
+        while weight > budget {
+            let Some(oldest) = history.pop_front() else {
+                break;
+            };
+            weight -= oldest.value.size();
+        }
+        self.weight.store(weight, Ordering::Relaxed);
+    }
+
+    // the retained revisions newer than `since_version`
+    pub fn history(&self, since_version: u64) -> Vec<Revision> {
+        let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        history
+            .iter()
+            .filter(|revision| revision.version > since_version)
+            .cloned()
+            .collect()
+    }
+
+    // every revision after `since_version` up to the current one, or `None`
+    // if some of them already dropped out of the history
+    pub async fn missed(&self, since_version: u64) -> Option<Vec<Revision>> {
+        let entry = self.data.read().await;
+        if since_version > entry.version {
+            return None;
+        }
+
+        let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        let complete = since_version == entry.version
+            || history
+                .iter()
+                .any(|revision| revision.version == since_version + 1);
+        if !complete {
+            return None;
+        }
+
+        Some(
+            history
+                .iter()
+                .filter(|revision| revision.version > since_version)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    pub fn revision(&self, version: u64) -> Option<Revision> {
+        let history = self.history.lock().unwrap_or_else(|e| e.into_inner());
+        history
+            .iter()
+            .find(|revision| revision.version == version)
+            .cloned()
     }
 
     pub async fn subscribe(&self, s: S) {
@@ -0,0 +1,98 @@
+use std::sync::Arc;
+
+use serde::{de::DeserializeOwned, Serialize};
+use specta::Type;
+
+// how the messages of a socket are encoded, negotiated with the
+// `Sec-WebSocket-Protocol` header. sockets that ask for none speak json
+#[derive(Type, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Json,
+    Msgpack,
+    Cbor,
+}
+
+// a message encoded for a protocol, shared by every socket it is sent to
+#[derive(Clone, Debug)]
+pub enum Payload {
+    Text(Arc<str>),
+    Binary(Arc<[u8]>),
+}
+
+impl Protocol {
+    pub const ALL: [Protocol; 3] = [Protocol::Json, Protocol::Msgpack, Protocol::Cbor];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Json => "json",
+            Protocol::Msgpack => "msgpack",
+            Protocol::Cbor => "cbor",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|protocol| protocol.name() == name)
+    }
+
+    pub fn encode<T>(self, message: &T) -> Result<Payload, String>
+    where
+        T: Serialize,
+    {
+        match self {
+            Protocol::Json => serde_json::to_string(message)
+                .map(|text| Payload::Text(text.into()))
+                .map_err(|e| e.to_string()),
+            // structs are encoded as maps, like in json, not as arrays
+            Protocol::Msgpack => rmp_serde::to_vec_named(message)
+                .map(|bytes| Payload::Binary(bytes.into()))
+                .map_err(|e| e.to_string()),
+            Protocol::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(message, &mut bytes).map_err(|e| e.to_string())?;
+                Ok(Payload::Binary(bytes.into()))
+            }
+        }
+    }
+
+    pub fn decode<M>(self, bytes: &[u8]) -> Result<M, String>
+    where
+        M: DeserializeOwned,
+    {
+        match self {
+            Protocol::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Protocol::Msgpack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            Protocol::Cbor => ciborium::from_reader(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// encodes a message at most once per protocol, for sending it to many sockets
+pub struct Encoded<'a, T> {
+    message: &'a T,
+    payloads: [Option<Payload>; 3],
+}
+
+impl<'a, T> Encoded<'a, T>
+where
+    T: Serialize,
+{
+    pub fn new(message: &'a T) -> Self {
+        Self {
+            message,
+            payloads: [None, None, None],
+        }
+    }
+
+    pub fn get(&mut self, protocol: Protocol) -> Result<Payload, String> {
+        let payload = &mut self.payloads[protocol as usize];
+        if let Some(payload) = payload {
+            return Ok(payload.clone());
+        }
+
+        let encoded = protocol.encode(self.message)?;
+        *payload = Some(encoded.clone());
+        Ok(encoded)
+    }
+}
@@ -0,0 +1,194 @@
+use std::{
+    io::{self, Write},
+    mem,
+};
+
+use flate2::write::DeflateDecoder;
+use soketto::{
+    base::{Header, OpCode},
+    extension::{deflate, Extension, Param},
+    BoxedError, Mode, Storage,
+};
+
+const CLIENT_NO_CONTEXT_TAKEOVER: &str = "client_no_context_takeover";
+
+// soketto's `permessage-deflate` with two changes for a server. every message
+// is inflated on its own, so clients are told not to keep their context
+// between messages, and nothing is inflated past `max_size`, a few kilobytes
+// of deflate can otherwise grow into gigabytes
+#[derive(Debug)]
+pub struct Deflate {
+    inner: deflate::Deflate,
+    params: Vec<Param<'static>>,
+    max_size: usize,
+    buffer: Vec<u8>,
+    await_last_fragment: bool,
+}
+
+impl Deflate {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            inner: deflate::Deflate::new(Mode::Server),
+            params: Vec::new(),
+            max_size,
+            buffer: Vec::new(),
+            await_last_fragment: false,
+        }
+    }
+}
+
+impl Extension for Deflate {
+    fn is_enabled(&self) -> bool {
+        self.inner.is_enabled()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn params(&self) -> &[Param<'_>] {
+        &self.params
+    }
+
+    fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError> {
+        // clients may offer several configurations, the first one that can be
+        // accepted is kept
+        if self.inner.is_enabled() {
+            return Ok(());
+        }
+
+        self.inner.configure(params)?;
+        self.params = self
+            .inner
+            .params()
+            .iter()
+            .filter(|param| param.name() != CLIENT_NO_CONTEXT_TAKEOVER)
+            .map(|param| param.clone().acquire())
+            .chain([Param::new(CLIENT_NO_CONTEXT_TAKEOVER)])
+            .collect();
+        Ok(())
+    }
+
+    fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
+        self.inner.encode(header, data)
+    }
+
+    // follows soketto's, which waits for the last fragment before inflating
+    // the whole message
+    fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        match header.opcode() {
+            OpCode::Binary | OpCode::Text if header.is_rsv1() => {
+                if !header.is_fin() {
+                    self.await_last_fragment = true;
+                    return Ok(());
+                }
+            }
+            OpCode::Continue if header.is_fin() && self.await_last_fragment => {
+                self.await_last_fragment = false;
+            }
+            _ => return Ok(()),
+        }
+
+        // the empty block the client stripped, see RFC 7692 7.2.2
+        data.extend_from_slice(&[0, 0, 0xFF, 0xFF]);
+
+        self.buffer.clear();
+        let mut decoder = DeflateDecoder::new(Bounded {
+            buffer: &mut self.buffer,
+            max_size: self.max_size,
+        });
+        decoder.write_all(data)?;
+        decoder.finish()?;
+        mem::swap(data, &mut self.buffer);
+
+        header.set_rsv1(false);
+        header.set_payload_len(data.len());
+        Ok(())
+    }
+
+    fn reserved_bits(&self) -> (bool, bool, bool) {
+        (true, false, false)
+    }
+}
+
+struct Bounded<'a> {
+    buffer: &'a mut Vec<u8>,
+    max_size: usize,
+}
+
+impl Write for Bounded<'_> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if self.buffer.len() + bytes.len() > self.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("inflated message is larger than {} bytes", self.max_size),
+            ));
+        }
+        self.buffer.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn negotiated(max_size: usize, offer: &[&'static str]) -> Deflate {
+        let mut deflate = Deflate::new(max_size);
+        let params: Vec<_> = offer.iter().map(|name| Param::new(*name)).collect();
+        deflate.configure(&params).unwrap();
+        deflate
+    }
+
+    fn compressed(deflate: &mut Deflate, text: &str) -> (Header, Vec<u8>) {
+        let mut header = Header::new(OpCode::Text);
+        let mut data = Storage::Shared(text.as_bytes());
+        deflate.encode(&mut header, &mut data).unwrap();
+        (header, data.as_ref().to_vec())
+    }
+
+    #[test]
+    fn asks_clients_not_to_keep_their_context() {
+        let names = |deflate: &Deflate| -> Vec<String> {
+            deflate
+                .params()
+                .iter()
+                .map(|param| param.name().to_owned())
+                .collect()
+        };
+
+        let deflate = negotiated(1024, &["client_max_window_bits"]);
+        assert!(deflate.is_enabled());
+        assert_eq!(names(&deflate), [CLIENT_NO_CONTEXT_TAKEOVER]);
+
+        let deflate = negotiated(1024, &[CLIENT_NO_CONTEXT_TAKEOVER]);
+        assert_eq!(names(&deflate), [CLIENT_NO_CONTEXT_TAKEOVER]);
+    }
+
+    #[test]
+    fn inflates_messages() {
+        let mut deflate = negotiated(1024, &[]);
+        let text = "a".repeat(1000);
+        let (mut header, mut data) = compressed(&mut deflate, &text);
+        assert!(header.is_rsv1() && data.len() < text.len());
+
+        deflate.decode(&mut header, &mut data).unwrap();
+        assert!(!header.is_rsv1());
+        assert_eq!(data, text.as_bytes());
+    }
+
+    #[test]
+    fn refuses_to_inflate_past_max_size() {
+        let mut deflate = negotiated(1024, &[]);
+        let (mut header, mut data) = compressed(&mut deflate, &"a".repeat(1025));
+        assert!(deflate.decode(&mut header, &mut data).is_err());
+    }
+}
@@ -1,5 +1,10 @@
+pub mod codec;
+mod deflate;
 pub mod pool;
 pub mod socket;
+pub mod upgrade;
+
+use std::sync::Arc;
 
 use serde::Deserialize;
 
@@ -9,6 +14,35 @@ where
     M: for<'a> Deserialize<'a> + Send + Sync,
 {
     pub socket_id: socket::SocketId,
-    pub message: M,
+    // frames that could not be turned into a message are passed on as well,
+    // so the listener can tell the client what went wrong
+    pub message: Result<M, FrameError>,
     pub tag: T,
 }
+
+#[derive(Clone, Debug)]
+pub enum FrameError {
+    Malformed(String),
+    TooLarge(usize),
+    RateLimited,
+}
+
+// lets the owner of a pool throttle its sockets, asked with the size of every
+// frame a socket sends before it is parsed
+pub trait Admit {
+    fn admit(&self, len: usize) -> Admission;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Allowed,
+    // the frame is rejected, the socket stays connected
+    Limited,
+    Disconnect,
+}
+
+impl<T: Admit> Admit for Arc<T> {
+    fn admit(&self, len: usize) -> Admission {
+        (**self).admit(len)
+    }
+}
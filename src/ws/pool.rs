@@ -1,16 +1,18 @@
-use std::sync::Arc;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use axum::extract::ws::{Message, WebSocket};
 use futures::{
-    channel::mpsc::{UnboundedReceiver, UnboundedSender},
-    SinkExt, StreamExt,
+    channel::mpsc::{Receiver, Sender},
+    SinkExt,
 };
-use moka::future::Cache;
+use hashbrown::HashMap;
 use serde::{Deserialize, Serialize};
+use soketto::{connection, Data, Incoming};
 
 use super::{
-    socket::{Socket, SocketId, SocketInner},
-    TaggedMessage,
+    codec::{Encoded, Protocol},
+    socket::{Overflow, Socket, SocketId, SocketInner, Topic},
+    upgrade::WebSocket,
+    Admission, Admit, FrameError, TaggedMessage,
 };
 
 pub type WebSocketPool<M, Tag> = Arc<WebSocketPoolInner<M, Tag>>;
@@ -18,72 +20,126 @@ pub struct WebSocketPoolInner<M, Tag>
 where
     M: for<'a> Deserialize<'a> + Send + Sync,
 {
-    sockets: Cache<SocketId, Socket>,
-    subscriber: UnboundedSender<TaggedMessage<M, Tag>>,
+    // every socket with the tag it was listened to with. only locked for
+    // queueing, never across an await
+    sockets: RwLock<HashMap<SocketId, (Socket, Tag)>>,
+    // bounded, so sockets stop being read while the listener is behind
+    subscriber: Sender<TaggedMessage<M, Tag>>,
+    // text frames larger than this are rejected without being parsed
+    max_message_size: usize,
+    // how many messages may be waiting to be written to each socket
+    queue: usize,
+    overflow: Overflow,
 }
 
 impl<M, Tag> WebSocketPoolInner<M, Tag>
 where
     M: for<'a> Deserialize<'a> + Send + Sync + 'static,
-    Tag: Clone + Send + Sync + 'static,
+    Tag: Admit + Clone + Send + Sync + 'static,
 {
-    pub fn new() -> (
-        WebSocketPool<M, Tag>,
-        UnboundedReceiver<TaggedMessage<M, Tag>>,
-    ) {
-        let (tx, rx) = futures::channel::mpsc::unbounded();
+    pub fn new(
+        max_message_size: usize,
+        inbound: usize,
+        queue: usize,
+        overflow: Overflow,
+    ) -> (WebSocketPool<M, Tag>, Receiver<TaggedMessage<M, Tag>>) {
+        let (tx, rx) = futures::channel::mpsc::channel(inbound);
         (
             Arc::new(Self {
-                sockets: Cache::builder().build(),
+                sockets: RwLock::new(HashMap::new()),
                 subscriber: tx,
+                max_message_size,
+                queue,
+                overflow,
             }),
             rx,
         )
     }
 
-    pub async fn add_socket(self: &Arc<Self>, socket: Socket) {
-        self.sockets.insert(socket.id, socket).await;
+    pub async fn add_socket(self: &Arc<Self>, socket: Socket, tag: Tag) {
+        self.write().insert(socket.id, (socket, tag));
     }
 
     pub async fn remove_socket(self: &Arc<Self>, id: SocketId) {
-        if let Some(socket) = self.sockets.remove(&id).await {
-            socket.terminate().await;
+        let removed = self.write().remove(&id);
+        if let Some((socket, _)) = removed {
+            socket.terminate();
         }
     }
 
-    pub async fn broadcast<T>(self: &Arc<Self>, message: T) -> Result<(), Error>
-    where
-        T: Serialize,
-    {
-        let message = serde_json::to_string(&message)?;
-        for (id, socket) in self.sockets.iter() {
-            if let Err(_) = socket.send(Message::Text(message.clone())).await {
-                self.remove_socket(*id).await;
-            }
+    // closes a single socket
+    pub async fn disconnect(self: &Arc<Self>, id: SocketId) {
+        self.remove_socket(id).await;
+    }
+
+    // whether messages about `topic` are coalesced while the socket is behind
+    pub async fn coalesce(self: &Arc<Self>, id: SocketId, topic: &str, enabled: bool) {
+        if let Some((socket, _)) = self.read().get(&id) {
+            socket.coalesce(topic, enabled);
         }
+    }
 
-        Ok(())
+    pub fn tagged(self: &Arc<Self>) -> Vec<(SocketId, Tag)> {
+        self.read()
+            .iter()
+            .map(|(id, (_, tag))| (*id, tag.clone()))
+            .collect()
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    pub fn connections(self: &Arc<Self>) -> u64 {
+        self.read().len() as u64
+    }
+
+    // the tag of a socket that is still connected
+    pub fn tag(self: &Arc<Self>, id: SocketId) -> Option<Tag> {
+        self.read().get(&id).map(|(_, tag)| tag.clone())
+    }
+
+    // waits until the socket is disconnected, right away if it is gone already
+    pub async fn closed(self: &Arc<Self>, id: SocketId) {
+        if let Some(socket) = self.socket(id) {
+            socket.closed().await;
+        }
+    }
+
+    // closes every socket and the inbound channel, ending the listener
+    pub async fn close(self: &Arc<Self>) {
+        self.subscriber.clone().close_channel();
+        let sockets = std::mem::take(&mut *self.write());
+        for (socket, _) in sockets.values() {
+            socket.terminate();
+        }
     }
 
     pub async fn send_to<T>(self: &Arc<Self>, id: &mut SocketId, message: T) -> Result<(), Error>
     where
         T: Serialize,
     {
-        if let Some(socket) = self.sockets.get(&id).await {
-            let message = serde_json::to_string(&message)?;
-            match socket.send(Message::Text(message)).await {
-                Err(_) => self.remove_socket(*id).await,
-                _ => *id = 0,
-            }
+        let Some(socket) = self.socket(*id) else {
+            return Ok(());
+        };
+
+        let payload = socket.protocol().encode(&message)?;
+        match socket.send(payload) {
+            Err(_) => self.remove_socket(*id).await,
+            _ => *id = 0,
         }
 
         Ok(())
     }
 
+    // the message is serialized once per protocol and the same frame is queued
+    // for every socket speaking it. the `topic` lets sockets that fall behind coalesce their queue,
+    // see `Overflow`
     pub async fn send_to_many<T>(
         self: &Arc<Self>,
         ids: &mut [SocketId],
         message: T,
+        topic: Option<Topic<'_>>,
     ) -> Result<(), Error>
     where
         T: Serialize,
@@ -92,67 +148,113 @@ where
             return Ok(());
         }
 
-        let message = serde_json::to_string(&message)?;
-        for id in ids {
-            if let Some(socket) = self.sockets.get(id).await {
-                match socket.send(Message::Text(message.clone())).await {
-                    Err(_) => self.remove_socket(*id).await,
-                    _ => *id = 0,
+        let mut encoded = Encoded::new(&message);
+        let mut failed = Vec::new();
+        {
+            let sockets = self.read();
+            for id in ids.iter_mut() {
+                if let Some((socket, _)) = sockets.get(id) {
+                    match socket.send_about(encoded.get(socket.protocol())?, topic) {
+                        Err(_) => failed.push(*id),
+                        _ => *id = 0,
+                    }
                 }
             }
         }
 
+        for id in failed {
+            self.remove_socket(id).await;
+        }
+
         Ok(())
     }
 
     pub async fn listen_to(self: &Arc<Self>, websocket: WebSocket, tag: Tag) -> SocketId {
+        // only a protocol the client asked for out of `Protocol::ALL` is picked
+        let protocol = websocket
+            .protocol()
+            .and_then(Protocol::from_name)
+            .unwrap_or_default();
         let (sink, mut stream) = websocket.split();
-        let socket = SocketInner::new(sink);
+        let socket = SocketInner::new(sink, protocol, self.queue, self.overflow);
         let id = socket.id;
 
-        self.add_socket(socket.clone()).await;
+        self.add_socket(socket.clone(), tag.clone()).await;
 
         let this = self.clone();
         tokio::task::spawn(async move {
             let mut subscriber = this.subscriber.clone();
-            println!("Listening to socket {}", socket.id);
-            while let Some(message) = stream.next().await {
+            debug!("Listening to socket {}", socket.id);
+            let mut bytes = Vec::new();
+            loop {
+                bytes.clear();
                 let result: Result<(), Error> = try {
-                    match message? {
-                        Message::Text(text) => {
-                            let message: M = match serde_json::from_str(&text) {
-                                Ok(m) => m,
-                                Err(e) => {
-                                    eprintln!("Error deserializing message: {}", e);
-                                    continue;
-                                }
-                            };
-
-                            subscriber
-                                .send(TaggedMessage {
-                                    tag: tag.clone(),
-                                    socket_id: socket.id,
-                                    message,
-                                })
-                                .await?;
+                    // text frames are always json, binary frames are in the
+                    // socket's protocol. pings are answered by soketto
+                    let protocol = match stream.receive(&mut bytes).await {
+                        Ok(Incoming::Data(Data::Text(_))) => Protocol::Json,
+                        Ok(Incoming::Data(Data::Binary(_))) => protocol,
+                        Ok(Incoming::Pong(_)) => continue,
+                        Ok(Incoming::Closed(_)) | Err(connection::Error::Closed) => break,
+                        Err(e) => Err(Error::from(e))?,
+                    };
+
+                    let message = match tag.admit(bytes.len()) {
+                        Admission::Disconnect => {
+                            info!(
+                                "Disconnecting socket {}, it kept exceeding its rate limits",
+                                socket.id
+                            );
+                            break;
                         }
-                        Message::Close(_) => break,
-                        _ => (),
-                    }
+                        Admission::Limited => Err(FrameError::RateLimited),
+                        Admission::Allowed if bytes.len() > this.max_message_size => {
+                            Err(FrameError::TooLarge(bytes.len()))
+                        }
+                        Admission::Allowed => {
+                            protocol.decode::<M>(&bytes).map_err(FrameError::Malformed)
+                        }
+                    };
+
+                    subscriber
+                        .send(TaggedMessage {
+                            tag: tag.clone(),
+                            socket_id: socket.id,
+                            message,
+                        })
+                        .await
+                        .map_err(Error::from)?;
                 };
 
                 if let Err(e) = result {
-                    eprintln!("Error sending message: {}", e);
+                    error!("Error sending message: {}", e);
                     break;
                 }
             }
 
             this.remove_socket(socket.id).await;
-            println!("Socket {} terminated", socket.id);
+            debug!("Socket {} terminated", socket.id);
         });
 
         id
     }
 }
 
+impl<M, Tag> WebSocketPoolInner<M, Tag>
+where
+    M: for<'a> Deserialize<'a> + Send + Sync,
+{
+    fn socket(&self, id: SocketId) -> Option<Socket> {
+        self.read().get(&id).map(|(socket, _)| socket.clone())
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<SocketId, (Socket, Tag)>> {
+        self.sockets.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<SocketId, (Socket, Tag)>> {
+        self.sockets.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
 type Error = Box<dyn std::error::Error + Send + Sync>;
@@ -1,11 +1,18 @@
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
-use axum::extract::ws::{Message, WebSocket};
-use futures::{stream::SplitSink, SinkExt};
-use tokio::sync::{Mutex, RwLock};
+use serde::Deserialize;
+use tokio::sync::Notify;
+
+use super::{
+    codec::{Payload, Protocol},
+    upgrade::Sink,
+};
 
 // sockets start at 1
 pub type SocketId = usize;
@@ -14,19 +21,79 @@ fn next_id() -> SocketId {
     NEXTID.fetch_add(1, Ordering::Relaxed)
 }
 
+// what happens to a message sent to a socket whose queue is full
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    // the oldest queued message is dropped
+    DropOldest,
+    // a message that replaces its topic drops every queued message of the
+    // same topic, e.g. a new value drops older changes of the same store.
+    // sockets are disconnected if that frees no room
+    #[default]
+    Coalesce,
+    Disconnect,
+}
+
+// what a message is about, for coalescing
+#[derive(Clone, Copy, Debug)]
+pub struct Topic<'a> {
+    pub key: &'a str,
+    // whether the message makes earlier messages of the topic redundant
+    pub replaces: bool,
+}
+
+// frames written to the connection at once, before flushing it
+const BATCH: usize = 32;
+
+struct Frame {
+    payload: Payload,
+    topic: Option<String>,
+}
+
+struct Queue {
+    frames: VecDeque<Frame>,
+    // topics whose waiting messages are replaced by newer ones right away
+    // instead of only on overflow
+    coalesced: HashSet<String>,
+    terminated: bool,
+}
+
+// messages are queued and written by a task of their own, so a slow client
+// only ever holds up itself
 pub type Socket = Arc<SocketInner>;
 pub struct SocketInner {
     pub(crate) id: SocketId,
-    sink: Mutex<SplitSink<WebSocket, Message>>,
-    terminated: RwLock<bool>,
+    protocol: Protocol,
+    queue: Mutex<Queue>,
+    queued: Notify,
+    // woken once the socket is terminated
+    closed: Notify,
+    capacity: usize,
+    overflow: Overflow,
 }
 
 impl SocketInner {
-    pub fn new(sink: SplitSink<WebSocket, Message>) -> Socket {
+    pub fn new(sink: Sink, protocol: Protocol, capacity: usize, overflow: Overflow) -> Socket {
+        let socket = Self::queue(protocol, capacity, overflow);
+        tokio::task::spawn(socket.clone().write(sink));
+        socket
+    }
+
+    // a socket that only queues its messages, `new` writes them to a connection
+    fn queue(protocol: Protocol, capacity: usize, overflow: Overflow) -> Socket {
         Arc::new(Self {
             id: next_id(),
-            sink: Mutex::new(sink),
-            terminated: RwLock::new(false),
+            protocol,
+            queue: Mutex::new(Queue {
+                frames: VecDeque::new(),
+                coalesced: HashSet::new(),
+                terminated: false,
+            }),
+            queued: Notify::new(),
+            closed: Notify::new(),
+            capacity,
+            overflow,
         })
     }
 
@@ -34,21 +101,133 @@ impl SocketInner {
         self.id
     }
 
-    pub async fn send(self: &Arc<Self>, message: Message) -> Result<(), &'static str> {
-        if *self.terminated.read().await {
+    // the encoding the socket's messages have to be sent in
+    pub fn protocol(self: &Arc<Self>) -> Protocol {
+        self.protocol
+    }
+
+    pub fn send(self: &Arc<Self>, payload: Payload) -> Result<(), &'static str> {
+        self.send_about(payload, None)
+    }
+
+    // queues a message, failing if the socket is terminated or has to be
+    // disconnected because its queue overflowed
+    pub fn send_about(
+        self: &Arc<Self>,
+        payload: Payload,
+        topic: Option<Topic>,
+    ) -> Result<(), &'static str> {
+        let mut queue = self.lock();
+        if queue.terminated {
             return Err("Socket is terminated");
         }
 
-        self.sink
-            .lock()
-            .await
-            .send(message)
-            .await
-            .map_err(|_| "Failed to send message")
+        if let Some(topic) = topic.filter(|topic| topic.replaces) {
+            if queue.coalesced.contains(topic.key) {
+                queue
+                    .frames
+                    .retain(|frame| frame.topic.as_deref() != Some(topic.key));
+            }
+        }
+
+        if queue.frames.len() >= self.capacity {
+            match (self.overflow, topic) {
+                (Overflow::DropOldest, _) => {
+                    queue.frames.pop_front();
+                }
+                (Overflow::Coalesce, Some(topic)) if topic.replaces => {
+                    queue
+                        .frames
+                        .retain(|frame| frame.topic.as_deref() != Some(topic.key));
+                    if queue.frames.len() >= self.capacity {
+                        return Err("Socket queue is full");
+                    }
+                }
+                _ => return Err("Socket queue is full"),
+            }
+        }
+
+        queue.frames.push_back(Frame {
+            payload,
+            topic: topic.map(|topic| topic.key.to_owned()),
+        });
+        drop(queue);
+        self.queued.notify_one();
+        Ok(())
+    }
+
+    pub fn coalesce(self: &Arc<Self>, topic: &str, enabled: bool) {
+        let mut queue = self.lock();
+        if enabled {
+            queue.coalesced.insert(topic.to_owned());
+        } else {
+            queue.coalesced.remove(topic);
+        }
+    }
+
+    // drops whatever is still queued and closes the connection
+    pub fn terminate(self: &Arc<Self>) {
+        let mut queue = self.lock();
+        queue.terminated = true;
+        queue.frames.clear();
+        drop(queue);
+        self.queued.notify_one();
+        self.closed.notify_waiters();
+    }
+
+    // waits until the socket is terminated
+    pub async fn closed(self: &Arc<Self>) {
+        loop {
+            let closed = self.closed.notified();
+            if self.lock().terminated {
+                return;
+            }
+            closed.await;
+        }
     }
 
-    pub async fn terminate(self: &Arc<Self>) {
-        *self.terminated.write().await = true;
+    fn lock(&self) -> std::sync::MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // writes whatever is queued in batches, only flushing the connection once
+    // per batch
+    async fn write(self: Arc<Self>, mut sink: Sink) {
+        loop {
+            let batch: Vec<Frame> = {
+                let mut queue = self.lock();
+                if queue.terminated {
+                    break;
+                }
+                let len = queue.frames.len().min(BATCH);
+                queue.frames.drain(..len).collect()
+            };
+
+            if batch.is_empty() {
+                self.queued.notified().await;
+                continue;
+            }
+
+            let result: Result<(), _> = try {
+                for frame in batch {
+                    match frame.payload {
+                        Payload::Text(text) => sink.send_text(&*text).await?,
+                        Payload::Binary(bytes) => sink.send_binary(&*bytes).await?,
+                    }
+                }
+                sink.flush().await?;
+            };
+            if result.is_err() {
+                let mut queue = self.lock();
+                queue.terminated = true;
+                queue.frames.clear();
+                drop(queue);
+                self.closed.notify_waiters();
+                return;
+            }
+        }
+
+        let _ = sink.close().await;
     }
 }
 
@@ -65,3 +244,87 @@ impl PartialEq for SocketInner {
 }
 
 impl Eq for SocketInner {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(text: &str) -> Payload {
+        Payload::Text(text.into())
+    }
+
+    fn topic(key: &str, replaces: bool) -> Option<Topic<'_>> {
+        Some(Topic { key, replaces })
+    }
+
+    // the text of every queued message, in order
+    fn queued(socket: &Socket) -> Vec<String> {
+        socket
+            .lock()
+            .frames
+            .iter()
+            .map(|frame| match &frame.payload {
+                Payload::Text(text) => text.to_string(),
+                Payload::Binary(_) => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drop_oldest_makes_room_for_new_messages() {
+        let socket = SocketInner::queue(Protocol::Json, 2, Overflow::DropOldest);
+        for text in ["a", "b", "c"] {
+            socket.send(payload(text)).unwrap();
+        }
+        assert_eq!(queued(&socket), ["b", "c"]);
+    }
+
+    #[test]
+    fn disconnect_fails_once_the_queue_is_full() {
+        let socket = SocketInner::queue(Protocol::Json, 2, Overflow::Disconnect);
+        socket.send(payload("a")).unwrap();
+        socket.send(payload("b")).unwrap();
+        assert!(socket.send(payload("c")).is_err());
+    }
+
+    #[test]
+    fn coalesce_replaces_queued_messages_of_the_topic_on_overflow() {
+        let socket = SocketInner::queue(Protocol::Json, 3, Overflow::Coalesce);
+        socket.send_about(payload("a1"), topic("a", true)).unwrap();
+        socket.send_about(payload("a2"), topic("a", false)).unwrap();
+        socket.send_about(payload("b1"), topic("b", true)).unwrap();
+
+        // a change of `b` doesn't replace anything, so there is no room for it
+        assert!(socket.send_about(payload("b2"), topic("b", false)).is_err());
+
+        socket.send_about(payload("a3"), topic("a", true)).unwrap();
+        assert_eq!(queued(&socket), ["b1", "a3"]);
+    }
+
+    #[test]
+    fn coalesce_fails_if_the_topic_frees_no_room() {
+        let socket = SocketInner::queue(Protocol::Json, 2, Overflow::Coalesce);
+        socket.send_about(payload("a1"), topic("a", true)).unwrap();
+        socket.send_about(payload("b1"), topic("b", true)).unwrap();
+        assert!(socket.send_about(payload("c1"), topic("c", true)).is_err());
+    }
+
+    #[test]
+    fn coalesced_topics_are_replaced_before_the_queue_is_full() {
+        let socket = SocketInner::queue(Protocol::Json, 8, Overflow::Coalesce);
+        socket.coalesce("a", true);
+        socket.send_about(payload("a1"), topic("a", true)).unwrap();
+        socket.send_about(payload("b1"), topic("b", true)).unwrap();
+        socket.send_about(payload("a2"), topic("a", true)).unwrap();
+        assert_eq!(queued(&socket), ["b1", "a2"]);
+    }
+
+    #[test]
+    fn terminated_sockets_take_no_messages() {
+        let socket = SocketInner::queue(Protocol::Json, 2, Overflow::DropOldest);
+        socket.send(payload("a")).unwrap();
+        socket.terminate();
+        assert!(queued(&socket).is_empty());
+        assert!(socket.send(payload("b")).is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderValue, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use hyper_util::rt::TokioIo;
+use soketto::handshake::http::{is_upgrade_request, Server};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::deflate::Deflate;
+
+pub type Sink = soketto::Sender<Io>;
+pub type Stream = soketto::Receiver<Io>;
+
+// takes the place of axum's `WebSocketUpgrade`, which can't negotiate
+// extensions. soketto answers the handshake and may agree on
+// `permessage-deflate` with the client
+pub struct WebSocketUpgrade {
+    request: Request<()>,
+    on_upgrade: OnUpgrade,
+    protocol: Option<&'static str>,
+    max_frame_size: usize,
+    max_message_size: usize,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for WebSocketUpgrade {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let mut request = Request::new(());
+        *request.headers_mut() = parts.headers.clone();
+        if !is_upgrade_request(&request) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let on_upgrade = parts
+            .extensions
+            .remove::<OnUpgrade>()
+            .ok_or(StatusCode::UPGRADE_REQUIRED)?;
+
+        Ok(Self {
+            request,
+            on_upgrade,
+            protocol: None,
+            max_frame_size: 16 << 20,
+            max_message_size: 64 << 20,
+        })
+    }
+}
+
+impl WebSocketUpgrade {
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
+    // also the most a compressed message may inflate to
+    pub fn max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+
+    // the first of `protocols` the client asked for is picked, like axum does
+    pub fn protocols(mut self, protocols: impl IntoIterator<Item = &'static str>) -> Self {
+        let requested = self
+            .request
+            .headers()
+            .get(header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        self.protocol = protocols
+            .into_iter()
+            .find(|protocol| requested.split(',').any(|name| name.trim() == *protocol));
+        self
+    }
+
+    pub fn on_upgrade<F, Fut>(self, callback: F) -> Response
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut server = Server::new();
+        server.add_extension(Box::new(Deflate::new(self.max_message_size)));
+        let response = match server.receive_request(&self.request) {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("Rejecting websocket handshake: {}", e);
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+        };
+
+        let Self {
+            on_upgrade,
+            protocol,
+            max_frame_size,
+            max_message_size,
+            ..
+        } = self;
+        tokio::task::spawn(async move {
+            let upgraded = match on_upgrade.await {
+                Ok(upgraded) => upgraded,
+                Err(e) => {
+                    debug!("Websocket upgrade failed: {}", e);
+                    return;
+                }
+            };
+
+            let mut builder = server.into_builder(Io(TokioIo::new(upgraded)));
+            builder.set_max_frame_size(max_frame_size);
+            builder.set_max_message_size(max_message_size);
+            let (sink, stream) = builder.finish();
+            callback(WebSocket {
+                sink,
+                stream,
+                protocol,
+            })
+            .await;
+        });
+
+        let mut response = response.map(|()| Body::empty());
+        if let Some(protocol) = protocol {
+            response.headers_mut().insert(
+                header::SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(protocol),
+            );
+        }
+        response
+    }
+}
+
+pub struct WebSocket {
+    sink: Sink,
+    stream: Stream,
+    protocol: Option<&'static str>,
+}
+
+impl WebSocket {
+    // the subprotocol picked during the handshake
+    pub fn protocol(&self) -> Option<&'static str> {
+        self.protocol
+    }
+
+    pub fn split(self) -> (Sink, Stream) {
+        (self.sink, self.stream)
+    }
+}
+
+// soketto is written against the `futures` io traits, hyper hands over a
+// tokio connection
+pub struct Io(TokioIo<Upgraded>);
+
+impl futures::io::AsyncRead for Io {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = ReadBuf::new(buf);
+        ready!(Pin::new(&mut self.0).poll_read(cx, &mut buf))?;
+        Poll::Ready(Ok(buf.filled().len()))
+    }
+}
+
+impl futures::io::AsyncWrite for Io {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}